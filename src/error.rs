@@ -2,7 +2,7 @@ use thiserror::Error;  //can use #[error("Invalid Instruction")]
 //yt: program specific errors
 use solana_program::program_error::ProgramError;
 
-#[derive(Error, Debug, Copy, Clone)]
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PerpError {
     /// Signature Mismatch
     #[error("Signature Mismatch")]
@@ -19,6 +19,99 @@ pub enum PerpError {
     /// Incorrect Admin
     #[error("Incorrect Admin")]
     IncorrectAdmin,
+    /// Instruction data ends before all fields are read
+    #[error("Instruction Truncated")]
+    InstructionTruncated,
+    /// Instruction data has bytes left after the last field
+    #[error("Instruction Trailing Bytes")]
+    InstructionTrailingBytes,
+    /// Symbol is not valid UTF-8
+    #[error("Invalid Symbol")]
+    InvalidSymbol,
+    /// Symbol is longer than MAX_SYMBOL_LEN
+    #[error("Symbol Too Long")]
+    SymbolTooLong,
+    /// Versioned instruction envelope has an unknown version
+    #[error("Unsupported Instruction Version")]
+    UnsupportedInstructionVersion,
+    /// Account address is not the expected canonical pda
+    #[error("Invalid PDA")]
+    InvalidPda,
+    /// Admin can only change through ProposeAdmin and AcceptAdmin
+    #[error("Admin Transfer Required")]
+    AdminTransferRequired,
+    /// No admin proposal to accept
+    #[error("No Pending Admin")]
+    NoPendingAdmin,
+    /// No queued config change to cancel
+    #[error("No Pending Config Change")]
+    NoPendingConfigChange,
+    /// Fewer valid signatures than the signer threshold
+    #[error("Threshold Not Met")]
+    ThresholdNotMet,
+    /// Recovered key is not in the signer set
+    #[error("Signer Not In Set")]
+    SignerNotInSet,
+    /// Recovered keys are not strictly ascending, duplicated signer
+    #[error("Signers Not Ascending")]
+    SignersNotAscending,
+    /// Signer set too large, duplicated or threshold out of range
+    #[error("Invalid Signer Set")]
+    InvalidSignerSet,
+    /// Recovery id is not 0..=3
+    #[error("Invalid Recovery Id")]
+    InvalidRecoveryId,
+    /// Signature s is above n/2, the malleable form
+    #[error("High S Signature")]
+    HighSSignature,
+    /// Signature can't be parsed or recovered
+    #[error("Invalid Signature")]
+    InvalidSignature,
+    /// Message hash has a wrong length
+    #[error("Invalid Signature Hash")]
+    InvalidSignatureHash,
+    /// Signer is not the gateway
+    #[error("Incorrect Gateway")]
+    IncorrectGateway,
+    /// Signer holds none of the roles allowed for the instruction
+    #[error("Unauthorized")]
+    Unauthorized,
+    /// Deposit or withdraw is paused globally or for the token
+    #[error("Paused")]
+    Paused,
+    /// Withdraw over the token rate limit
+    #[error("Rate Limited")]
+    RateLimited,
+    /// Withdraw over the max amount of one withdraw
+    #[error("Withdraw Amount Too Large")]
+    WithdrawAmountTooLarge,
+    /// Withdraw over the user daily cap
+    #[error("Daily Cap Exceeded")]
+    DailyCapExceeded,
+    /// Withdraw needs the admin or gateway co-signature
+    #[error("Cosignature Required")]
+    CosignatureRequired,
+    /// Withdraw timestamp is not after now
+    #[error("Withdraw Expired")]
+    WithdrawExpired,
+    /// Withdraw timestamp is further than max_withdraw_validity from now
+    #[error("Withdraw Validity Too Long")]
+    WithdrawValidityTooLong,
+    /// Account is in an older layout, MigrateAccount first
+    #[error("Migration Required")]
+    MigrationRequired,
+    /// Account data does not start with the discriminator of the expected account
+    #[error("Invalid Discriminator")]
+    InvalidDiscriminator,
+    /// User account has a withdraw id window for MAX_ACCOUNT_TYPES account types
+    #[error("Withdraw Id Map Full")]
+    WithdrawIdMapFull,
+    /// User account holds the daily withdrawn amount of MAX_DAILY_TOKENS tokens per day
+    #[error("Daily Tokens Full")]
+    DailyTokensFull,
+    /// Symbol is empty
+    #[error("Empty Symbol")]
+    EmptySymbol,
 }
 
 //yt: From trait to covert PerpError to ProgramError
@@ -1,53 +1,96 @@
 use solana_program::{
+    instruction::{AccountMeta, Instruction},
     msg,
     program_error::ProgramError,
     program_error::ProgramError::InvalidInstructionData,
     pubkey::Pubkey,
+    system_program,
 };
 use std::str;
 use std::convert::TryInto;
-// use crate::{
-//     error::PerpError, 
-//     error::PerpError::InvalidInstruction};
+use std::mem::size_of;
+use borsh::{BorshDeserialize, BorshSerialize};
+use crate::{
+    find_account_address, find_perpetual_address, find_token_address,
+    error::PerpError,
+    signature::SignatureScheme,
+    state::TypeSymbol,
+};
+
+/// Max bytes of a token symbol
+pub const MAX_SYMBOL_LEN: usize = 16;
+
+/// Checks a token symbol is 1..=MAX_SYMBOL_LEN bytes, what the token config and its pda seeds hold,
+/// PerpError::EmptySymbol or PerpError::SymbolTooLong
+pub fn validate_symbol(symbol: &str) -> Result<(), PerpError> {
+    if symbol.is_empty() {
+        return Err(PerpError::EmptySymbol);
+    }
+    if symbol.len() > MAX_SYMBOL_LEN {
+        return Err(PerpError::SymbolTooLong);
+    }
+    Ok(())
+}
+/// First byte of a versioned instruction: tag, version, borsh body
+/// tags 0-4 are the legacy hand packed layout
+pub const VERSIONED_INSTRUCTION_TAG: u8 = 0xFF;
+/// Current version of the borsh instruction body
+pub const INSTRUCTION_VERSION: u8 = 1;
 
 //yt: program API, (de)serializing instruction data
 //<'a>named lifetime parameter have to be added
+//borsh encodes the variant index, only append new variants at the end
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub enum PerpetualInstruction {
     /// Initializes an perpetual account
     /// Accounts expected:
-    /// 0. `[signer]` The admin of the perpetual account.
-    ///    check signer
+    /// 0. `[signer, writable]` The admin of the perpetual account.
+    ///    check signer, pays for the new account
     /// 1. `[writable]` The perpetual account
     ///    new created, 
     ///    not initialized or signer is data.admin, account address is pda(unique)
     /// 2. `[]` system account for create_account cpi
+    ///
     /// Safety:
     /// 1.The perpetual account is the only pda of the program.
     ///   1st initialise can be done by anyone that can be admin
-    ///   fake perpetual account is owned by other program, because
-    ///   create_account need new created account's signature 
-    /// 2.admin can reconfigure the perpetual account, including change admin
-    /// 3.Perpetual::unpack ensure initial
+    ///   account address must be the canonical pda, else PerpError::InvalidPda
+    /// 2.admin can reconfigure the perpetual account, but not change admin,
+    ///   a different admin fails with PerpError::AdminTransferRequired, see ProposeAdmin
+    /// 3.reconfigured secp256k1_pubkey and gateway are queued as QueueConfigChange
+    /// 4.Perpetual::load ensure initial
     InitPerpetual {
         /// The signer eth public key to check signature
         secp256k1_pubkey: [u8; 64],
         /// The gateway to send trades and withdraw
-        gateway: Pubkey, //not used currently
-        /// The admin of the Perpetual account
+        gateway: Pubkey, //signs BatchWithdraw, RelayedWithdraw
+        /// The admin of the Perpetual account, only set on 1st initialise
         admin: Pubkey,
+        /// Canonical bump of the perpetual pda computed off-chain, optional, must match
+        /// the one the program finds on creation, or the stored one on an update, checked
+        /// with one create_program_address instead of find_program_address, legacy layout: one trailing byte
+        bump_seed: Option<u8>,
     },
 
-    /// Set Token supported map
-    /// 0. `[signer]` The admin account to update the token map
-    ///    check signer
-    /// 1. `[writable]` The perpetual account
-    ///    check signer is admin, initialized
+    /// Add, update or remove a supported token, stored in its own token config account
+    /// 0. `[signer, writable]` The admin account to update the token
+    ///    check signer, pays the rent of a new token config account
+    /// 1. `[]` The perpetual account
+    ///    check owner, signer is admin, initialized
     /// 2. `[]` program token account
     ///    check token owner is pda, owner is spl::token
+    /// 3. `[writable]` The token config account
+    ///    check address is the pda of [TOKEN_SEED, account type, symbol]
+    /// 4. `[]` The system program, only needed to add a token
+    /// 5. `[]` The mint of the program token account, only needed to add a token
+    ///
     /// Safety:
     /// 1.only admin
     /// 2.program_token_account owner is pda(perpetual account address)
-    /// 3.if program token account is not owned by spl, remove the token
+    /// 3.if program token account is not owned by spl, remove the token,
+    ///   the token config account is checked like any load (owner, discriminator, pda) and closed to the admin,
+    ///   no data and owned by the system program, a removed token then fails to load with UninitializedAccount
+    /// 4.an updated token keeps its limits and flags
     SetTokenMap {
         /// account type
         account_type: u8,
@@ -58,28 +101,37 @@ pub enum PerpetualInstruction {
     /// Initializes a new perpetual user account to hold user's trading data in the perpetual exchange
     /// 
     /// Accounts expected:
-    /// 0. `[signer]` user
+    /// 0. `[signer, writable]` user
+    ///    pays for the new account
     /// 1. `[writable]` The perpetual user account to initialize.
     ///    check rent exempt, not initialized, address is pda
     /// 2. `[]` system account for create_account cpi
+    ///
     /// Safety:
-    /// 1. user account is pda
+    /// 1. user account is the canonical pda, else PerpError::InvalidPda
     /// 2. once
-    InitAccount,
+    InitAccount {
+        /// Canonical bump of the user account pda computed off-chain, optional, must match
+        /// the one the program finds and stores, legacy layout: one trailing byte
+        bump_seed: Option<u8>,
+    },
 
     /// Deposit
     /// Accounts expected:
     /// 0. `[signer]` The token owner of depositor's token account
     ///    check signer
     /// 1. `[writable]` The depositor's token account
-    ///    check mint is in perpetual user account's btreemap
+    ///    check mint is the token config's mint
     /// 2. `[writable]` The program token account
-    ///    check address is in perpetual account's btreemap
+    ///    check address is the token config's program token account
     /// 3. `[]` The perpetual user account
     ///    check owner, user is signer, initialized, to avoid can't withdraw
     /// 4. `[]` The perpetual account
     ///    check owner, initialized
     /// 5. `[]` The token program
+    /// 6. `[]` The token config account
+    ///    check owner, account type and symbol
+    ///
     /// Safety:
     /// 1.fake depositor's token account v
     /// 2.fake program token account v
@@ -104,13 +156,18 @@ pub enum PerpetualInstruction {
     /// 1. `[writable]` The user token account
     ///    check mint, initialized, token owner is signer
     /// 2. `[writable]` The program token account
-    ///    check mint, intialized, address is in perpetual accout btreemap
+    ///    check mint, intialized, address is the token config's program token account
     /// 3. `[writable]` The perpetual user account
     ///    check initialized, user is signer, owner is program
     /// 4. `[]` The perpetual account
     ///    check initialized, owner is programid
     /// 5. `[]` The token program
-    ///     check spl::id
+    ///    check spl::id
+    /// 6. `[writable]` The token config account
+    ///    check owner, account type and symbol, counts the token rate limit
+    /// 7. `[signer]` Optional co-signer, the admin or gateway
+    ///    required from the token's cosign_amount
+    ///
     /// Safety:
     /// 1.fake user token account 
     /// 2.fake program token account 
@@ -120,6 +177,11 @@ pub enum PerpetualInstruction {
     /// 6.incorrect account type or symbol 
     /// 7.incorrect amount
     /// 8.incorrect signature
+    ///   signed message is signature::WithdrawMessage, bound to program, perpetual account,
+    ///   user token account and cluster
+    /// 9.amount over the token's max_withdraw_amount, user_daily_cap or rate limit
+    /// 10.timestamp not in (now, now + max_withdraw_validity]
+    /// 11.withdraw id used, dead or above state::MAX_WITHDRAW_ID
     Withdraw {
         /// account type
         account_type: u8,
@@ -137,71 +199,442 @@ pub enum PerpetualInstruction {
         signature: [u8; 64], //64
     },
 
+    /// Propose a new admin, it takes over after AcceptAdmin
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.only admin
+    /// 2.a new proposal replaces the pending one
+    ProposeAdmin {
+        /// The proposed admin
+        new_admin: Pubkey,
+    },
+
+    /// Accept the admin proposal
+    /// Accounts expected:
+    /// 0. `[signer]` The pending admin
+    ///    check signer is pending admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.only pending admin, proves the new admin key is usable
+    AcceptAdmin,
+
+    /// Cancel the admin proposal
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    CancelAdminProposal,
+
+    /// Queue a signer key or gateway change, effective after CONFIG_TIMELOCK
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.only admin
+    /// 2.withdraw keeps the old key until the delay elapses, users can react
+    /// 3.a new change replaces the queued one of the same kind and restarts the delay
+    QueueConfigChange {
+        /// the change
+        change: ConfigChange,
+    },
+
+    /// Cancel every queued config change which is not effective yet
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    CancelConfigChange,
+
+    /// Withdraw authorized by signer_threshold signatures of the signer set
+    /// required from the token's multisig_amount
+    /// Accounts expected: same as Withdraw
+    ///
+    /// Safety: same as Withdraw, plus
+    /// 1.every recovered key is in the active signer set
+    /// 2.recovered keys are strictly ascending, no key counts twice
+    /// 3.at least signer_threshold keys
+    WithdrawMultisig {
+        /// account type
+        account_type: u8,
+        /// token symbol
+        symbol: String,
+        /// withdraw amount
+        amount: u64,
+        /// withdraw id
+        withdrawid: u64,
+        /// time
+        timestamp: u64,
+        /// signatures ordered by recovered key
+        signatures: Vec<RecoverableSignature>,
+    },
+
+    /// Set the withdraw amount of a token from which the signer set must sign
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[]` The perpetual account
+    ///    check owner, initialized
+    /// 2. `[writable]` The token config account
+    ///    check owner, account type and symbol
+    SetMultisigAmount {
+        /// account type
+        account_type: u8,
+        /// token symbol
+        symbol: String,
+        /// 0 never requires the signer set
+        amount: u64,
+    },
+
+    /// Set how withdraw messages are hashed before signing, Keccak or Eip712
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.only admin
+    /// 2.signatures of the other scheme stop verifying at once
+    SetSignatureScheme {
+        /// the scheme
+        scheme: SignatureScheme,
+    },
+
+    /// Burn an issued withdraw id so its signature can't be submitted,
+    /// the backend can then re-issue a corrected withdraw with a new id
+    /// Accounts expected:
+    /// 0. `[signer]` The admin or gateway, or any fee payer with a signature
+    ///    check signer is admin or gateway when no signature
+    /// 1. `[writable]` The perpetual user account
+    ///    check owner, initialized
+    /// 2. `[]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.authorized by the admin, the gateway or by the secp256k1 signer over
+    ///   signature::CancelWithdrawMessage
+    /// 2.a used or dead withdraw id, or one above the window of WITHDRAW_WINDOW ids
+    ///   from the lowest unused one, fails with WithdrawIdFail, a cancel never moves the window
+    ///   and never creates one, an account type without a withdraw yet fails too
+    /// 3.only withdraws move the window, so between two withdraws of the user a compromised
+    ///   gateway burns at most the WITHDRAW_WINDOW ids of the window per account type,
+    ///   a denial the user retries with new ids
+    CancelWithdrawId {
+        /// account type
+        account_type: u8,
+        /// withdraw id to burn
+        withdrawid: u64,
+        /// secp256k1 signer authorization, none for the admin or gateway
+        signature: Option<RecoverableSignature>,
+    },
+
+    /// Withdraw many signed payouts in one transaction, submitted by the gateway
+    /// Accounts expected:
+    /// 0. `[signer]` The gateway
+    ///    check signer is the active gateway
+    /// 1. `[]` The perpetual account
+    ///    check owner, initialized, unpacked once
+    /// 2. `[]` The token program
+    ///    check spl::id
+    ///
+    /// then 4 accounts of each entry i, from account 3+4*i:
+    /// 0. `[writable]` The user token account
+    ///    check mint, token owner is the account user
+    /// 1. `[writable]` The program token account
+    ///    check address is the token config's program token account
+    /// 2. `[writable]` The perpetual user account
+    ///    check owner, initialized
+    /// 3. `[writable]` The token config account
+    ///    check owner, account type and symbol, counts the token rate limit
+    ///
+    /// Safety:
+    /// 1.every entry has the checks of Withdraw, except the user signer
+    /// 2.funds only go to token accounts owned by the account user
+    /// 3.one failed entry fails the whole batch
+    /// 4.the gateway signer is the co-signer of every entry
+    BatchWithdraw {
+        /// the payouts
+        entries: Vec<BatchWithdrawEntry>,
+    },
+
+    /// Withdraw relayed by the gateway, the user doesn't sign or pay fees
+    /// Accounts expected:
+    /// 0. `[signer]` The gateway
+    ///    check signer is the active gateway
+    /// 1..6. same as Withdraw
+    ///
+    /// Safety: same as Withdraw, plus
+    /// 1.the user token account owner is the account user, funds only go to the user
+    /// 2.the secp256k1 signature still authorizes amount and destination
+    /// 3.the gateway signer is the co-signer
+    RelayedWithdraw {
+        /// account type
+        account_type: u8,
+        /// token symbol
+        symbol: String,
+        /// withdraw amount
+        amount: u64,
+        /// withdraw id
+        withdrawid: u64,
+        /// time
+        timestamp: u64,
+        /// one signature, or the signer set ordered by recovered key
+        signatures: Vec<RecoverableSignature>,
+    },
+
+    /// Set the pause flags, PAUSE_DEPOSIT | PAUSE_WITHDRAW, globally or of one token
+    /// Accounts expected:
+    /// 0. `[signer]` The admin, gateway or a guardian
+    ///    check signer role
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized, only written for the global flags
+    /// 2. `[writable]` The token config account, only for a token
+    ///    check owner, account type and symbol
+    ///
+    /// Safety:
+    /// 1.gateway and guardians can only add flags, clearing a flag is admin only
+    /// 2.paused Deposit and Withdraw fail with PerpError::Paused
+    SetPause {
+        /// none for the global flags
+        token: Option<TypeSymbol>,
+        /// the new flags
+        flags: u8,
+    },
+
+    /// Replace the guardian set, keys that can only engage pause flags
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.at most MAX_GUARDIANS keys, no duplicates
+    /// 2.a guardian can not unpause, withdraw or change config
+    SetGuardians {
+        /// the new guardian set, empty removes all guardians
+        guardians: Vec<Pubkey>,
+    },
+
+    /// Set the max amount of a token withdrawn over a rolling window
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[]` The perpetual account
+    ///    check owner, initialized
+    /// 2. `[writable]` The token config account
+    ///    check owner, account type and symbol
+    ///
+    /// Safety:
+    /// 1.over the limit withdraws fail with PerpError::RateLimited
+    /// 2.a new window restarts the count, same window keeps it
+    SetRateLimit {
+        /// account type
+        account_type: u8,
+        /// token symbol
+        symbol: String,
+        /// window in seconds, 0 removes the limit
+        window: u64,
+        /// max amount withdrawn over any window
+        max_amount: u64,
+    },
+
+    /// Set the withdraw amount limits of a token
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[]` The perpetual account
+    ///    check owner, initialized
+    /// 2. `[writable]` The token config account
+    ///    check owner, account type and symbol
+    ///
+    /// Safety:
+    /// 1.a withdraw over max_amount fails with PerpError::WithdrawAmountTooLarge
+    /// 2.a user over user_daily_cap in a UTC day fails with PerpError::DailyCapExceeded,
+    ///   a user withdraws at most state::MAX_DAILY_TOKENS capped tokens a day,
+    ///   the next one fails with PerpError::DailyTokensFull
+    /// 3.a withdraw from cosign_amount without the admin or gateway signer
+    ///   fails with PerpError::CosignatureRequired
+    SetWithdrawLimits {
+        /// account type
+        account_type: u8,
+        /// token symbol
+        symbol: String,
+        /// max amount of one withdraw, 0 is no limit
+        max_amount: u64,
+        /// max amount of one user in a day, 0 is no limit
+        user_daily_cap: u64,
+        /// amount from which a co-signature is required, 0 is never
+        cosign_amount: u64,
+    },
+
+    /// Set the max seconds from now to a withdraw timestamp
+    /// Accounts expected:
+    /// 0. `[signer]` The admin
+    ///    check signer is admin
+    /// 1. `[writable]` The perpetual account
+    ///    check owner, initialized
+    ///
+    /// Safety:
+    /// 1.a timestamp not after now fails with PerpError::WithdrawExpired
+    /// 2.a timestamp after now + validity fails with PerpError::WithdrawValidityTooLong
+    SetMaxWithdrawValidity {
+        /// seconds, 0 is no limit
+        validity: u64,
+    },
+
+    /// Rewrite a legacy account, without a discriminator, to the current layout
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The payer
+    ///    check signer, pays the rent of a grown account, gets back the rent of a shrunk one
+    /// 1. `[writable]` The perpetual account
+    ///    check owner
+    /// 2. `[]` The system program
+    ///
+    /// legacy perpetual account, payer is its admin:
+    /// 3.. `[writable]` token config account, `[]` mint, for each token of the token map in order
+    ///    check address is the pda of [TOKEN_SEED, account type, symbol], mint of the token
+    ///    a token with an empty symbol or one over MAX_SYMBOL_LEN takes no accounts and is dropped,
+    ///    the admin adds it again with SetTokenMap under a valid symbol
+    ///    the tokens without accounts stay in the legacy account for the next MigrateAccount,
+    ///    the perpetual account is rewritten once its token map is empty
+    ///
+    /// otherwise:
+    /// 3. `[writable]` The legacy user account
+    ///    check owner, payer is the user or the admin
+    ///
+    /// Safety:
+    /// 1.accounts of the current layout fail with ProgramError::AccountAlreadyInitialized
+    /// 2.every other instruction fails on a legacy account with PerpError::MigrationRequired
+    /// 3.withdraw ids up to the last used one of a legacy user account are dead
+    MigrateAccount,
+
+}
+
+/// One payout of BatchWithdraw
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BatchWithdrawEntry {
+    /// account type
+    pub account_type: u8,
+    /// token symbol
+    pub symbol: String,
+    /// withdraw amount
+    pub amount: u64,
+    /// withdraw id
+    pub withdrawid: u64,
+    /// time
+    pub timestamp: u64,
+    /// one signature, or the signer set ordered by recovered key
+    pub signatures: Vec<RecoverableSignature>,
+}
+
+/// Accounts of one BatchWithdraw entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchWithdrawAccounts {
+    /// The user token account
+    pub user_token_account: Pubkey,
+    /// The program token account
+    pub program_token_account: Pubkey,
+    /// The user of the perpetual user account
+    pub user: Pubkey,
+}
+
+/// secp256k1 signature with its recovery id
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RecoverableSignature {
+    /// recovery id
+    pub recovery_id: u8,
+    /// signature
+    pub signature: [u8; 64],
+}
+
+/// Timelocked perpetual config
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum ConfigChange {
+    /// The signer eth public key to check signature
+    Secp256k1Pubkey([u8; 64]),
+    /// The gateway
+    Gateway(Pubkey),
+    /// The M-of-N withdraw signer set, empty with threshold 0 disables it
+    SignerSet {
+        /// secp256k1 keys, at most MAX_SIGNERS
+        signers: Vec<[u8; 64]>,
+        /// signatures required, 1..=signers.len()
+        threshold: u8,
+    },
 }
 
 impl PerpetualInstruction {
     /// Unpacks a byte buffer into a [PerpetualInstruction](enum.PerpetualInstruction.html).
+    /// Every length is checked, malformed data returns an error and never panics.
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         let (&tag, rest) = input.split_first().ok_or(InvalidInstructionData)?;
-        Ok(match tag {
+        if tag == VERSIONED_INSTRUCTION_TAG {
+            return Self::unpack_versioned(rest);
+        }
+        let (instruction, rest) = match tag {
             0 => {
                 msg!("Perpetual instuction InitPerpetual");
-                let (value, rest ) = rest.split_at(64);
-                let secp256k1_pubkey= value.to_vec().try_into().unwrap();
-                //let (signer_eth_pubkey, rest) = Self::unpack_pubkey(rest)?;
-                let (gateway, rest) = Self::unpack_pubkey(rest)?;
-                let (admin, _rest) = Self::unpack_pubkey(rest)?;
-                Self::InitPerpetual{
+                let (secp256k1_pubkey, rest) = Self::next_array::<64>(rest)?;
+                let (gateway, rest) = Self::next_pubkey(rest)?;
+                let (admin, rest) = Self::next_pubkey(rest)?;
+                let (bump_seed, rest) = Self::next_bump_seed(rest)?;
+                (Self::InitPerpetual{
                     secp256k1_pubkey,
                     gateway,
                     admin,
-                }
+                    bump_seed,
+                }, rest)
             },
             1 => {
                 msg!("Perpetual instuction SetTokenMap");
-                let (&account_type, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (&symbol_len, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (symbol_raw, _rest) = rest.split_at(symbol_len as usize);
-                let symbol = str::from_utf8(symbol_raw).unwrap().to_string();
-                Self::SetTokenMap{
+                let (account_type, rest) = Self::next_u8(rest)?;
+                let (symbol, rest) = Self::next_symbol(rest)?;
+                (Self::SetTokenMap{
                     account_type,
                     symbol,
-                }
+                }, rest)
             }
             2 => {
                 msg!("Perpetual instuction InitAccount");
-                //let (user, _rest) = Self::unpack_pubkey(rest)?;
-                Self::InitAccount
+                let (bump_seed, rest) = Self::next_bump_seed(rest)?;
+                (Self::InitAccount{
+                    bump_seed,
+                }, rest)
             },
             3 => {
                 msg!("Perpetual instuction Deposit");
-                let (&account_type, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (&symbol_len, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (symbol_raw, rest) = rest.split_at(symbol_len as usize);
-                let symbol = str::from_utf8(symbol_raw).unwrap().to_string();
-                let amount = Self::unpack_u64(rest)?;
-                Self::Deposit{
+                let (account_type, rest) = Self::next_u8(rest)?;
+                let (symbol, rest) = Self::next_symbol(rest)?;
+                let (amount, rest) = Self::next_u64(rest)?;
+                (Self::Deposit{
                     account_type,
                     symbol,
                     amount,
-                }
+                }, rest)
             },
             4 => {
                 msg!("Perpetual instuction Withdraw");
-                let (&account_type, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (&symbol_len, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (symbol_raw, rest) = rest.split_at(symbol_len as usize);
-                let symbol = str::from_utf8(symbol_raw).unwrap().to_string();
-                let (value, rest) = rest.split_at(8);
-                let amount = Self::unpack_u64(value)?;
-                let (value, rest) = rest.split_at(8);
-                let withdrawid = Self::unpack_u64(value)?;
-                let (value, rest) = rest.split_at(8);
-                let timestamp = Self::unpack_u64(value)?;
-                let (&recovery_id, rest) = rest.split_first().ok_or(InvalidInstructionData)?;
-                let (value,_rest ) = rest.split_at(64);
-                let signature= value.to_vec().try_into().unwrap();
-                Self::Withdraw{
+                let (account_type, rest) = Self::next_u8(rest)?;
+                let (symbol, rest) = Self::next_symbol(rest)?;
+                let (amount, rest) = Self::next_u64(rest)?;
+                let (withdrawid, rest) = Self::next_u64(rest)?;
+                let (timestamp, rest) = Self::next_u64(rest)?;
+                let (recovery_id, rest) = Self::next_u8(rest)?;
+                let (signature, rest) = Self::next_array::<64>(rest)?;
+                (Self::Withdraw{
                     account_type,
                     symbol,
                     amount,
@@ -209,10 +642,187 @@ impl PerpetualInstruction {
                     timestamp,
                     recovery_id,
                     signature,
-                }
+                }, rest)
             },
             _ => return Err(InvalidInstructionData),
-        })
+        };
+        if !rest.is_empty() {
+            msg!("Perpetual instruction trailing bytes:{}", rest.len());
+            return Err(PerpError::InstructionTrailingBytes.into());
+        }
+        Ok(instruction)
+    }
+
+    //version byte + borsh body, borsh rejects trailing bytes
+    fn unpack_versioned(input: &[u8]) -> Result<Self, ProgramError> {
+        let (version, body) = Self::next_u8(input)?;
+        if version != INSTRUCTION_VERSION {
+            msg!("Perpetual unsupported instruction version:{}", version);
+            return Err(PerpError::UnsupportedInstructionVersion.into());
+        }
+        let instruction = Self::try_from_slice(body).map_err(|_| InvalidInstructionData)?;
+        instruction.check_symbol()?;
+        Ok(instruction)
+    }
+
+    //same symbol rule as the legacy layout
+    fn check_symbol(&self) -> Result<(), ProgramError> {
+        if let Self::BatchWithdraw { entries } = self {
+            return entries.iter().try_for_each(|entry| Self::check_symbol_len(&entry.symbol));
+        }
+        if let Self::SetPause { token: Some(token), .. } = self {
+            return Self::check_symbol_len(&token.symbol);
+        }
+        let symbol = match self {
+            Self::SetTokenMap { symbol, .. }
+            | Self::Deposit { symbol, .. }
+            | Self::Withdraw { symbol, .. }
+            | Self::WithdrawMultisig { symbol, .. }
+            | Self::RelayedWithdraw { symbol, .. }
+            | Self::SetMultisigAmount { symbol, .. }
+            | Self::SetRateLimit { symbol, .. }
+            | Self::SetWithdrawLimits { symbol, .. } => symbol,
+            _ => return Ok(()),
+        };
+        Self::check_symbol_len(symbol)
+    }
+
+    fn check_symbol_len(symbol: &str) -> Result<(), ProgramError> {
+        Ok(validate_symbol(symbol)?)
+    }
+
+    fn next_u8(input: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
+        let (&value, rest) = input.split_first().ok_or(PerpError::InstructionTruncated)?;
+        Ok((value, rest))
+    }
+
+    //optional last byte of the legacy layout
+    fn next_bump_seed(input: &[u8]) -> Result<(Option<u8>, &[u8]), ProgramError> {
+        if input.is_empty() {
+            return Ok((None, input));
+        }
+        let (bump_seed, rest) = Self::next_u8(input)?;
+        Ok((Some(bump_seed), rest))
+    }
+
+    fn next_u64(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
+        let (value, rest) = Self::next_array::<8>(input)?;
+        Ok((u64::from_le_bytes(value), rest))
+    }
+
+    fn next_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
+        let (value, rest) = Self::next_array::<32>(input)?;
+        Ok((Pubkey::new_from_array(value), rest))
+    }
+
+    fn next_array<const N: usize>(input: &[u8]) -> Result<([u8; N], &[u8]), ProgramError> {
+        if input.len() < N {
+            return Err(PerpError::InstructionTruncated.into());
+        }
+        let (value, rest) = input.split_at(N);
+        let value = value.try_into().map_err(|_| PerpError::InstructionTruncated)?;
+        Ok((value, rest))
+    }
+
+    //symbol is u8 length prefixed utf-8, 1..=MAX_SYMBOL_LEN bytes
+    fn next_symbol(input: &[u8]) -> Result<(String, &[u8]), ProgramError> {
+        let (symbol_len, rest) = Self::next_u8(input)?;
+        let symbol_len = symbol_len as usize;
+        if symbol_len == 0 {
+            return Err(PerpError::EmptySymbol.into());
+        }
+        if symbol_len > MAX_SYMBOL_LEN {
+            return Err(PerpError::SymbolTooLong.into());
+        }
+        if rest.len() < symbol_len {
+            return Err(PerpError::InstructionTruncated.into());
+        }
+        let (symbol_raw, rest) = rest.split_at(symbol_len);
+        let symbol = str::from_utf8(symbol_raw).map_err(|_| PerpError::InvalidSymbol)?;
+        Ok((symbol.to_string(), rest))
+    }
+
+    /// Packs a [PerpetualInstruction](enum.PerpetualInstruction.html) into the versioned
+    /// envelope: VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION, borsh body.
+    /// PerpError::EmptySymbol or PerpError::SymbolTooLong for a symbol the program would reject.
+    pub fn pack_versioned(&self) -> Result<Vec<u8>, ProgramError> {
+        self.check_symbol()?;
+        let mut buf = vec![VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION];
+        //writing into a Vec can't fail
+        self.serialize(&mut buf).expect("serialize into Vec");
+        Ok(buf)
+    }
+
+    /// Packs a [PerpetualInstruction](enum.PerpetualInstruction.html) into a byte buffer.
+    /// Instructions with a legacy tag use the legacy layout, deployed clients keep working.
+    /// PerpError::EmptySymbol or PerpError::SymbolTooLong for a symbol the program would reject.
+    pub fn pack(&self) -> Result<Vec<u8>, ProgramError> {
+        let mut buf = Vec::with_capacity(size_of::<Self>());
+        match self {
+            Self::InitPerpetual {
+                secp256k1_pubkey,
+                gateway,
+                admin,
+                bump_seed,
+            } => {
+                buf.push(0);
+                buf.extend_from_slice(secp256k1_pubkey);
+                buf.extend_from_slice(gateway.as_ref());
+                buf.extend_from_slice(admin.as_ref());
+                buf.extend(bump_seed);
+            }
+            Self::SetTokenMap {
+                account_type,
+                symbol,
+            } => {
+                buf.push(1);
+                buf.push(*account_type);
+                Self::pack_symbol(symbol, &mut buf)?;
+            }
+            Self::InitAccount { bump_seed } => {
+                buf.push(2);
+                buf.extend(bump_seed);
+            }
+            Self::Deposit {
+                account_type,
+                symbol,
+                amount,
+            } => {
+                buf.push(3);
+                buf.push(*account_type);
+                Self::pack_symbol(symbol, &mut buf)?;
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::Withdraw {
+                account_type,
+                symbol,
+                amount,
+                withdrawid,
+                timestamp,
+                recovery_id,
+                signature,
+            } => {
+                buf.push(4);
+                buf.push(*account_type);
+                Self::pack_symbol(symbol, &mut buf)?;
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&withdrawid.to_le_bytes());
+                buf.extend_from_slice(&timestamp.to_le_bytes());
+                buf.push(*recovery_id);
+                buf.extend_from_slice(signature);
+            }
+            //no legacy tag
+            _ => return self.pack_versioned(),
+        }
+        Ok(buf)
+    }
+
+    //symbol is u8 length prefixed, 1..=MAX_SYMBOL_LEN bytes
+    fn pack_symbol(symbol: &str, buf: &mut Vec<u8>) -> Result<(), ProgramError> {
+        Self::check_symbol_len(symbol)?;
+        buf.push(symbol.len() as u8);
+        buf.extend_from_slice(symbol.as_bytes());
+        Ok(())
     }
 
     pub fn unpack_bool(input: &u8) -> Result<bool, ProgramError> {
@@ -254,10 +864,938 @@ impl PerpetualInstruction {
     pub fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
         if input.len() >= 32 {
             let (key, rest) = input.split_at(32);
-            let pk = Pubkey::try_from(key).unwrap();
+            let pk = Pubkey::try_from(key).map_err(|_| InvalidInstructionData)?;
             Ok((pk, rest))
         } else {
             Err(InvalidInstructionData)
         }
     }
 }
+
+/// Creates an `InitPerpetual` instruction.
+pub fn init_perpetual(
+    program_id: &Pubkey,
+    admin_signer: &Pubkey,
+    secp256k1_pubkey: [u8; 64],
+    gateway: &Pubkey,
+    admin: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (perpetual, bump_seed) = find_perpetual_address(program_id);
+    let data = PerpetualInstruction::InitPerpetual {
+        secp256k1_pubkey,
+        gateway: *gateway,
+        admin: *admin,
+        bump_seed: Some(bump_seed),
+    }
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new(*admin_signer, true),
+        AccountMeta::new(perpetual, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `SetTokenMap` instruction.
+/// A `program_token_account` not owned by spl-token removes the token.
+pub fn set_token_map(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    program_token_account: &Pubkey,
+    mint: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetTokenMap {
+        account_type,
+        symbol: symbol.to_string(),
+    }
+    .pack()?;
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new_readonly(perpetual, false),
+        AccountMeta::new_readonly(*program_token_account, false),
+        AccountMeta::new(token_config, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*mint, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `InitAccount` instruction.
+pub fn init_account(program_id: &Pubkey, user: &Pubkey) -> Result<Instruction, ProgramError> {
+    let (account, bump_seed) = find_account_address(program_id, user);
+    let data = PerpetualInstruction::InitAccount {
+        bump_seed: Some(bump_seed),
+    }
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(account, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `Deposit` instruction.
+pub fn deposit(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_token_account: &Pubkey,
+    program_token_account: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::Deposit {
+        account_type,
+        symbol: symbol.to_string(),
+        amount,
+    }
+    .pack()?;
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (account, _) = find_account_address(program_id, user);
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    let accounts = vec![
+        AccountMeta::new_readonly(*user, true),
+        AccountMeta::new(*user_token_account, false),
+        AccountMeta::new(*program_token_account, false),
+        AccountMeta::new_readonly(account, false),
+        AccountMeta::new_readonly(perpetual, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(token_config, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `Withdraw` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_token_account: &Pubkey,
+    program_token_account: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    amount: u64,
+    withdrawid: u64,
+    timestamp: u64,
+    recovery_id: u8,
+    signature: [u8; 64],
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::Withdraw {
+        account_type,
+        symbol: symbol.to_string(),
+        amount,
+        withdrawid,
+        timestamp,
+        recovery_id,
+        signature,
+    }
+    .pack()?;
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    Ok(withdraw_instruction(program_id, user, user, user_token_account, program_token_account, &token_config, data))
+}
+
+/// Creates a `WithdrawMultisig` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_multisig(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_token_account: &Pubkey,
+    program_token_account: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    amount: u64,
+    withdrawid: u64,
+    timestamp: u64,
+    signatures: Vec<RecoverableSignature>,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::WithdrawMultisig {
+        account_type,
+        symbol: symbol.to_string(),
+        amount,
+        withdrawid,
+        timestamp,
+        signatures,
+    }
+    .pack()?;
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    Ok(withdraw_instruction(program_id, user, user, user_token_account, program_token_account, &token_config, data))
+}
+
+/// Adds the admin or gateway co-signer to a `Withdraw` or `WithdrawMultisig` instruction.
+pub fn add_withdraw_cosigner(instruction: &mut Instruction, cosigner: &Pubkey) {
+    instruction.accounts.push(AccountMeta::new_readonly(*cosigner, true));
+}
+
+/// Creates a `RelayedWithdraw` instruction signed and paid by the gateway.
+#[allow(clippy::too_many_arguments)]
+pub fn relayed_withdraw(
+    program_id: &Pubkey,
+    gateway: &Pubkey,
+    user: &Pubkey,
+    user_token_account: &Pubkey,
+    program_token_account: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    amount: u64,
+    withdrawid: u64,
+    timestamp: u64,
+    signatures: Vec<RecoverableSignature>,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::RelayedWithdraw {
+        account_type,
+        symbol: symbol.to_string(),
+        amount,
+        withdrawid,
+        timestamp,
+        signatures,
+    }
+    .pack()?;
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    Ok(withdraw_instruction(program_id, gateway, user, user_token_account, program_token_account, &token_config, data))
+}
+
+fn withdraw_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    user: &Pubkey,
+    user_token_account: &Pubkey,
+    program_token_account: &Pubkey,
+    token_config: &Pubkey,
+    data: Vec<u8>,
+) -> Instruction {
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (account, _) = find_account_address(program_id, user);
+    let accounts = vec![
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new(*user_token_account, false),
+        AccountMeta::new(*program_token_account, false),
+        AccountMeta::new(account, false),
+        AccountMeta::new_readonly(perpetual, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(*token_config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates a `ProposeAdmin` instruction.
+pub fn propose_admin(program_id: &Pubkey, admin: &Pubkey, new_admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::ProposeAdmin {
+        new_admin: *new_admin,
+    }
+    .pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates an `AcceptAdmin` instruction.
+pub fn accept_admin(program_id: &Pubkey, pending_admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::AcceptAdmin.pack()?;
+    Ok(admin_instruction(program_id, pending_admin, data))
+}
+
+/// Creates a `CancelAdminProposal` instruction.
+pub fn cancel_admin_proposal(program_id: &Pubkey, admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::CancelAdminProposal.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `QueueConfigChange` instruction.
+pub fn queue_config_change(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    change: ConfigChange,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::QueueConfigChange { change }.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `CancelConfigChange` instruction.
+pub fn cancel_config_change(program_id: &Pubkey, admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::CancelConfigChange.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `SetMultisigAmount` instruction.
+pub fn set_multisig_amount(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetMultisigAmount {
+        account_type,
+        symbol: symbol.to_string(),
+        amount,
+    }
+    .pack()?;
+    Ok(token_admin_instruction(program_id, admin, account_type, symbol, data))
+}
+
+/// Creates a `SetSignatureScheme` instruction.
+pub fn set_signature_scheme(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    scheme: SignatureScheme,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetSignatureScheme { scheme }.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `CancelWithdrawId` instruction.
+/// `authority` is the admin or gateway, or a fee payer when `signature` is set,
+/// `withdrawid` must be inside the window of `account_type`.
+pub fn cancel_withdraw_id(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    user: &Pubkey,
+    account_type: u8,
+    withdrawid: u64,
+    signature: Option<RecoverableSignature>,
+) -> Result<Instruction, ProgramError> {
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (account, _) = find_account_address(program_id, user);
+    let data = PerpetualInstruction::CancelWithdrawId {
+        account_type,
+        withdrawid,
+        signature,
+    }
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(account, false),
+        AccountMeta::new_readonly(perpetual, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `BatchWithdraw` instruction, `accounts[i]` are the accounts of `entries[i]`,
+/// `ProgramError::InvalidArgument` unless there are as many accounts as entries.
+pub fn batch_withdraw(
+    program_id: &Pubkey,
+    gateway: &Pubkey,
+    entries: Vec<BatchWithdrawEntry>,
+    accounts: &[BatchWithdrawAccounts],
+) -> Result<Instruction, ProgramError> {
+    if entries.len() != accounts.len() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let mut metas = vec![
+        AccountMeta::new_readonly(*gateway, true),
+        AccountMeta::new_readonly(perpetual, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    for (entry, entry_accounts) in entries.iter().zip(accounts) {
+        PerpetualInstruction::check_symbol_len(&entry.symbol)?;
+        let (account, _) = find_account_address(program_id, &entry_accounts.user);
+        let (token_config, _) = find_token_address(program_id, entry.account_type, &entry.symbol);
+        metas.push(AccountMeta::new(entry_accounts.user_token_account, false));
+        metas.push(AccountMeta::new(entry_accounts.program_token_account, false));
+        metas.push(AccountMeta::new(account, false));
+        metas.push(AccountMeta::new(token_config, false));
+    }
+    let data = PerpetualInstruction::BatchWithdraw { entries }.pack()?;
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: metas,
+        data,
+    })
+}
+
+/// Creates a `SetPause` instruction, `token` none sets the global flags.
+pub fn set_pause(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    token: Option<TypeSymbol>,
+    flags: u8,
+) -> Result<Instruction, ProgramError> {
+    match token {
+        Some(token) => {
+            let TypeSymbol { account_type, symbol } = token.clone();
+            let data = PerpetualInstruction::SetPause { token: Some(token), flags }.pack()?;
+            Ok(token_admin_instruction(program_id, authority, account_type, &symbol, data))
+        }
+        None => {
+            let data = PerpetualInstruction::SetPause { token: None, flags }.pack()?;
+            Ok(admin_instruction(program_id, authority, data))
+        }
+    }
+}
+
+/// Creates a `SetGuardians` instruction.
+pub fn set_guardians(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    guardians: Vec<Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetGuardians { guardians }.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `SetRateLimit` instruction.
+pub fn set_rate_limit(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    window: u64,
+    max_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetRateLimit {
+        account_type,
+        symbol: symbol.to_string(),
+        window,
+        max_amount,
+    }
+    .pack()?;
+    Ok(token_admin_instruction(program_id, admin, account_type, symbol, data))
+}
+
+/// Creates a `SetWithdrawLimits` instruction.
+pub fn set_withdraw_limits(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    max_amount: u64,
+    user_daily_cap: u64,
+    cosign_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetWithdrawLimits {
+        account_type,
+        symbol: symbol.to_string(),
+        max_amount,
+        user_daily_cap,
+        cosign_amount,
+    }
+    .pack()?;
+    Ok(token_admin_instruction(program_id, admin, account_type, symbol, data))
+}
+
+/// Creates a `SetMaxWithdrawValidity` instruction.
+pub fn set_max_withdraw_validity(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    validity: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::SetMaxWithdrawValidity { validity }.pack()?;
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `MigrateAccount` instruction for the legacy perpetual account,
+/// `tokens` are the (account type, symbol, mint) of the start of its remaining token map in order,
+/// tokens the program drops for their symbol are skipped.
+pub fn migrate_perpetual(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tokens: &[(u8, &str, Pubkey)],
+) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::MigrateAccount.pack()?;
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let mut accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(perpetual, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for (account_type, symbol, mint) in tokens {
+        if PerpetualInstruction::check_symbol_len(symbol).is_err() {
+            continue;
+        }
+        let (token_config, _) = find_token_address(program_id, *account_type, symbol);
+        accounts.push(AccountMeta::new(token_config, false));
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `MigrateAccount` instruction for the legacy user account of `user`.
+pub fn migrate_account(program_id: &Pubkey, payer: &Pubkey, user: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = PerpetualInstruction::MigrateAccount.pack()?;
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (account, _) = find_account_address(program_id, user);
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(perpetual, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(account, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+//signer + perpetual account, the layout of the config instructions
+fn admin_instruction(program_id: &Pubkey, signer: &Pubkey, data: Vec<u8>) -> Instruction {
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new(perpetual, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+//signer + perpetual account + token config account, the layout of the token config instructions
+fn token_admin_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    account_type: u8,
+    symbol: &str,
+    data: Vec<u8>,
+) -> Instruction {
+    let (perpetual, _) = find_perpetual_address(program_id);
+    let (token_config, _) = find_token_address(program_id, account_type, symbol);
+    let accounts = vec![
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new_readonly(perpetual, false),
+        AccountMeta::new(token_config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rng;
+
+    fn perp_err(e: PerpError) -> Result<PerpetualInstruction, ProgramError> {
+        Err(e.into())
+    }
+
+    //one instruction of every legacy tag
+    fn legacy_instructions() -> Vec<PerpetualInstruction> {
+        vec![
+            PerpetualInstruction::InitPerpetual {
+                secp256k1_pubkey: [3; 64],
+                gateway: Pubkey::new_unique(),
+                admin: Pubkey::new_unique(),
+                bump_seed: Some(254),
+            },
+            PerpetualInstruction::SetTokenMap { account_type: 1, symbol: "USDC".to_string() },
+            PerpetualInstruction::InitAccount { bump_seed: Some(255) },
+            PerpetualInstruction::Deposit { account_type: 2, symbol: "SOL".to_string(), amount: u64::MAX },
+            PerpetualInstruction::Withdraw {
+                account_type: 3,
+                symbol: "A".repeat(MAX_SYMBOL_LEN),
+                amount: 1,
+                withdrawid: 2,
+                timestamp: 3,
+                recovery_id: 1,
+                signature: [9; 64],
+            },
+        ]
+    }
+
+    //(key, signer, writable) of each account
+    fn metas(instruction: &Instruction) -> Vec<(Pubkey, bool, bool)> {
+        instruction
+            .accounts
+            .iter()
+            .map(|meta| (meta.pubkey, meta.is_signer, meta.is_writable))
+            .collect()
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        let mut instructions = legacy_instructions();
+        instructions.push(PerpetualInstruction::InitPerpetual {
+            secp256k1_pubkey: [0; 64],
+            gateway: Pubkey::default(),
+            admin: Pubkey::default(),
+            bump_seed: None,
+        });
+        instructions.push(PerpetualInstruction::InitAccount { bump_seed: None });
+        for instruction in instructions {
+            let data = instruction.pack().unwrap();
+            //legacy tag, not the versioned envelope
+            assert!(data[0] <= 4);
+            assert_eq!(PerpetualInstruction::unpack(&data), Ok(instruction));
+        }
+        //legacy layout of a deployed client, no bump seed
+        let mut data = vec![2];
+        assert_eq!(PerpetualInstruction::unpack(&data), Ok(PerpetualInstruction::InitAccount { bump_seed: None }));
+        data.push(7);
+        assert_eq!(PerpetualInstruction::unpack(&data), Ok(PerpetualInstruction::InitAccount { bump_seed: Some(7) }));
+    }
+
+    #[test]
+    fn versioned_round_trip() {
+        let signature = RecoverableSignature { recovery_id: 1, signature: [4; 64] };
+        let instructions = vec![
+            PerpetualInstruction::ProposeAdmin { new_admin: Pubkey::new_unique() },
+            PerpetualInstruction::AcceptAdmin,
+            PerpetualInstruction::CancelAdminProposal,
+            PerpetualInstruction::QueueConfigChange { change: ConfigChange::Secp256k1Pubkey([2; 64]) },
+            PerpetualInstruction::QueueConfigChange { change: ConfigChange::Gateway(Pubkey::new_unique()) },
+            PerpetualInstruction::QueueConfigChange {
+                change: ConfigChange::SignerSet { signers: vec![[5; 64], [6; 64]], threshold: 2 },
+            },
+            PerpetualInstruction::CancelConfigChange,
+            PerpetualInstruction::WithdrawMultisig {
+                account_type: 1,
+                symbol: "USDC".to_string(),
+                amount: 2,
+                withdrawid: 3,
+                timestamp: 4,
+                signatures: vec![signature, signature],
+            },
+            PerpetualInstruction::SetMultisigAmount { account_type: 1, symbol: "A".repeat(MAX_SYMBOL_LEN), amount: 9 },
+            PerpetualInstruction::SetSignatureScheme { scheme: SignatureScheme::Eip712 },
+            PerpetualInstruction::CancelWithdrawId { account_type: 1, withdrawid: 7, signature: None },
+            PerpetualInstruction::CancelWithdrawId { account_type: 1, withdrawid: 7, signature: Some(signature) },
+            PerpetualInstruction::BatchWithdraw {
+                entries: vec![BatchWithdrawEntry {
+                    account_type: 0,
+                    symbol: "SOL".to_string(),
+                    amount: 5,
+                    withdrawid: 6,
+                    timestamp: 7,
+                    signatures: vec![signature],
+                }],
+            },
+            PerpetualInstruction::RelayedWithdraw {
+                account_type: 2,
+                symbol: "ETH".to_string(),
+                amount: u64::MAX,
+                withdrawid: 0,
+                timestamp: 1,
+                signatures: vec![signature],
+            },
+            PerpetualInstruction::SetPause { token: None, flags: 3 },
+            PerpetualInstruction::SetPause { token: Some(TypeSymbol { account_type: 1, symbol: "USDC".to_string() }), flags: 1 },
+            PerpetualInstruction::SetGuardians { guardians: vec![Pubkey::new_unique(), Pubkey::new_unique()] },
+            PerpetualInstruction::SetRateLimit { account_type: 1, symbol: "USDC".to_string(), window: 60, max_amount: 100 },
+            PerpetualInstruction::SetWithdrawLimits {
+                account_type: 1,
+                symbol: "USDC".to_string(),
+                max_amount: 1,
+                user_daily_cap: 2,
+                cosign_amount: 3,
+            },
+            PerpetualInstruction::SetMaxWithdrawValidity { validity: 600 },
+            PerpetualInstruction::MigrateAccount,
+        ];
+        for instruction in instructions {
+            let data = instruction.pack().unwrap();
+            //0xFF, version 1, then the borsh body
+            assert_eq!(data[..2], [VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION]);
+            assert_eq!(PerpetualInstruction::unpack(&data), Ok(instruction.clone()));
+            //any other version of the same body is rejected
+            for version in [0, INSTRUCTION_VERSION + 1, u8::MAX] {
+                let mut unknown = data.clone();
+                unknown[1] = version;
+                assert_eq!(PerpetualInstruction::unpack(&unknown), perp_err(PerpError::UnsupportedInstructionVersion));
+            }
+        }
+        //the legacy instructions also round trip through the envelope
+        for instruction in legacy_instructions() {
+            let data = instruction.pack_versioned().unwrap();
+            assert_eq!(data[..2], [VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION]);
+            assert_eq!(PerpetualInstruction::unpack(&data), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn legacy_builders_account_order() {
+        let program_id = Pubkey::new_unique();
+        let (admin, gateway, user) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (user_token, program_token, mint) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (perpetual, perpetual_bump) = find_perpetual_address(&program_id);
+        let (account, account_bump) = find_account_address(&program_id, &user);
+        let (token_config, _) = find_token_address(&program_id, 1, "USDC");
+        let token_program = spl_token::id();
+        let system = system_program::id();
+
+        //InitPerpetual: admin, perpetual, system program
+        let ix = init_perpetual(&program_id, &admin, [1; 64], &gateway, &admin).unwrap();
+        assert_eq!(ix.program_id, program_id);
+        assert_eq!(metas(&ix), vec![(admin, true, true), (perpetual, false, true), (system, false, false)]);
+        assert_eq!(
+            PerpetualInstruction::unpack(&ix.data),
+            Ok(PerpetualInstruction::InitPerpetual { secp256k1_pubkey: [1; 64], gateway, admin, bump_seed: Some(perpetual_bump) })
+        );
+
+        //SetTokenMap: admin, perpetual, program token, token config, system program, mint
+        let ix = set_token_map(&program_id, &admin, &program_token, &mint, 1, "USDC").unwrap();
+        assert_eq!(
+            metas(&ix),
+            vec![
+                (admin, true, true),
+                (perpetual, false, false),
+                (program_token, false, false),
+                (token_config, false, true),
+                (system, false, false),
+                (mint, false, false),
+            ]
+        );
+        assert_eq!(
+            PerpetualInstruction::unpack(&ix.data),
+            Ok(PerpetualInstruction::SetTokenMap { account_type: 1, symbol: "USDC".to_string() })
+        );
+
+        //InitAccount: user, user account, system program
+        let ix = init_account(&program_id, &user).unwrap();
+        assert_eq!(metas(&ix), vec![(user, true, true), (account, false, true), (system, false, false)]);
+        assert_eq!(PerpetualInstruction::unpack(&ix.data), Ok(PerpetualInstruction::InitAccount { bump_seed: Some(account_bump) }));
+
+        //Deposit: user, user token, program token, user account, perpetual, token program, token config
+        let ix = deposit(&program_id, &user, &user_token, &program_token, 1, "USDC", 10).unwrap();
+        assert_eq!(
+            metas(&ix),
+            vec![
+                (user, true, false),
+                (user_token, false, true),
+                (program_token, false, true),
+                (account, false, false),
+                (perpetual, false, false),
+                (token_program, false, false),
+                (token_config, false, false),
+            ]
+        );
+        assert_eq!(
+            PerpetualInstruction::unpack(&ix.data),
+            Ok(PerpetualInstruction::Deposit { account_type: 1, symbol: "USDC".to_string(), amount: 10 })
+        );
+
+        //Withdraw: user, user token, program token, user account, perpetual, token program, token config
+        let ix = withdraw(&program_id, &user, &user_token, &program_token, 1, "USDC", 10, 11, 12, 1, [5; 64]).unwrap();
+        let expected = vec![
+            (user, true, false),
+            (user_token, false, true),
+            (program_token, false, true),
+            (account, false, true),
+            (perpetual, false, false),
+            (token_program, false, false),
+            (token_config, false, true),
+        ];
+        assert_eq!(metas(&ix), expected);
+        assert_eq!(
+            PerpetualInstruction::unpack(&ix.data),
+            Ok(PerpetualInstruction::Withdraw {
+                account_type: 1,
+                symbol: "USDC".to_string(),
+                amount: 10,
+                withdrawid: 11,
+                timestamp: 12,
+                recovery_id: 1,
+                signature: [5; 64],
+            })
+        );
+        //optional co-signer last
+        let mut ix = ix;
+        add_withdraw_cosigner(&mut ix, &gateway);
+        assert_eq!(metas(&ix)[..7], expected[..]);
+        assert_eq!(metas(&ix)[7], (gateway, true, false));
+    }
+
+    #[test]
+    fn batch_withdraw_accounts_per_entry() {
+        let (program_id, gateway) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let entry = BatchWithdrawEntry {
+            account_type: 1,
+            symbol: "USDC".to_string(),
+            amount: 1,
+            withdrawid: 1,
+            timestamp: 1,
+            signatures: vec![],
+        };
+        let entry_accounts = BatchWithdrawAccounts {
+            user: Pubkey::new_unique(),
+            user_token_account: Pubkey::new_unique(),
+            program_token_account: Pubkey::new_unique(),
+        };
+
+        let ix = batch_withdraw(&program_id, &gateway, vec![entry.clone(); 2], &[entry_accounts; 2]).unwrap();
+        assert_eq!(ix.accounts.len(), 3 + 2 * 4);
+        //a missing entry or missing accounts fail instead of being dropped
+        let mismatch = Some(ProgramError::InvalidArgument);
+        assert_eq!(batch_withdraw(&program_id, &gateway, vec![entry.clone(); 2], &[entry_accounts]).err(), mismatch);
+        assert_eq!(batch_withdraw(&program_id, &gateway, vec![entry], &[entry_accounts; 2]).err(), mismatch);
+        assert_eq!(batch_withdraw(&program_id, &gateway, vec![], &[entry_accounts]).err(), mismatch);
+    }
+
+    #[test]
+    fn pack_rejects_bad_symbols() {
+        let program_id = Pubkey::new_unique();
+        let (admin, user, token) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        //empty, past MAX_SYMBOL_LEN, past the u8 length byte and past the 32 byte pda seed
+        for (symbol, e) in [
+            ("", PerpError::EmptySymbol),
+            (&"A".repeat(MAX_SYMBOL_LEN + 1), PerpError::SymbolTooLong),
+            (&"A".repeat(33), PerpError::SymbolTooLong),
+            (&"A".repeat(300), PerpError::SymbolTooLong),
+        ] {
+            let rejected = Some(e.into());
+            let legacy = PerpetualInstruction::Deposit { account_type: 0, symbol: symbol.to_string(), amount: 1 };
+            assert_eq!(legacy.pack().err(), rejected);
+            let versioned = PerpetualInstruction::SetMultisigAmount { account_type: 0, symbol: symbol.to_string(), amount: 1 };
+            assert_eq!(versioned.pack().err(), rejected);
+            assert_eq!(set_token_map(&program_id, &admin, &token, &token, 0, symbol).err(), rejected);
+            assert_eq!(deposit(&program_id, &user, &token, &token, 0, symbol, 1).err(), rejected);
+            assert_eq!(withdraw(&program_id, &user, &token, &token, 0, symbol, 1, 1, 1, 0, [0; 64]).err(), rejected);
+            assert_eq!(set_rate_limit(&program_id, &admin, 0, symbol, 1, 1).err(), rejected);
+            let pause = Some(TypeSymbol { account_type: 0, symbol: symbol.to_string() });
+            assert_eq!(set_pause(&program_id, &admin, pause, 1).err(), rejected);
+            let entry = BatchWithdrawEntry {
+                account_type: 0,
+                symbol: symbol.to_string(),
+                amount: 1,
+                withdrawid: 1,
+                timestamp: 1,
+                signatures: vec![],
+            };
+            let entry_accounts = BatchWithdrawAccounts { user, user_token_account: token, program_token_account: token };
+            assert_eq!(batch_withdraw(&program_id, &admin, vec![entry], &[entry_accounts]).err(), rejected);
+            //the program drops the token, no accounts
+            assert_eq!(migrate_perpetual(&program_id, &admin, &[(0, symbol, token)]).unwrap().accounts.len(), 3);
+        }
+    }
+
+    #[test]
+    fn unpack_random_never_panics() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20_000 {
+            //every legacy tag and the versioned tag with any version
+            for tag in [0, 1, 2, 3, 4, VERSIONED_INSTRUCTION_TAG] {
+                let len = (rng.next() % 256) as usize;
+                let mut input = vec![tag];
+                if tag == VERSIONED_INSTRUCTION_TAG && rng.next() & 1 == 0 {
+                    input.push(INSTRUCTION_VERSION);
+                }
+                input.extend(rng.bytes(len));
+                let _ = PerpetualInstruction::unpack(&input);
+            }
+            let len = (rng.next() % 64) as usize;
+            let _ = PerpetualInstruction::unpack(&rng.bytes(len));
+        }
+    }
+
+    #[test]
+    fn unpack_truncated_never_panics() {
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        let mut instructions = legacy_instructions();
+        instructions.push(PerpetualInstruction::BatchWithdraw {
+            entries: vec![BatchWithdrawEntry {
+                account_type: 0,
+                symbol: "USDC".to_string(),
+                amount: 5,
+                withdrawid: 6,
+                timestamp: 7,
+                signatures: vec![RecoverableSignature { recovery_id: 0, signature: [1; 64] }],
+            }],
+        });
+        instructions.push(PerpetualInstruction::SetGuardians { guardians: vec![Pubkey::new_unique()] });
+        for instruction in instructions {
+            let data = instruction.pack().unwrap();
+            //every prefix fails, but the optional trailing bump seed, and random bytes in place of each byte
+            for len in 0..data.len() {
+                assert!(PerpetualInstruction::unpack(&data[..len]).is_err() || len + 1 == data.len());
+            }
+            for _ in 0..200 {
+                let mut corrupt = data.clone();
+                let at = (rng.next() as usize) % corrupt.len();
+                corrupt[at] = rng.next() as u8;
+                let _ = PerpetualInstruction::unpack(&corrupt);
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_typed_errors() {
+        let withdraw = PerpetualInstruction::Withdraw {
+            account_type: 0,
+            symbol: "USDC".to_string(),
+            amount: 1,
+            withdrawid: 1,
+            timestamp: 1,
+            recovery_id: 0,
+            signature: [0; 64],
+        }
+        .pack().unwrap();
+        //truncated fields
+        assert_eq!(PerpetualInstruction::unpack(&withdraw[..withdraw.len() - 1]), perp_err(PerpError::InstructionTruncated));
+        assert_eq!(PerpetualInstruction::unpack(&[3, 0]), perp_err(PerpError::InstructionTruncated));
+        assert_eq!(PerpetualInstruction::unpack(&[3, 0, 4, b'U']), perp_err(PerpError::InstructionTruncated));
+        assert_eq!(PerpetualInstruction::unpack(&[VERSIONED_INSTRUCTION_TAG]), perp_err(PerpError::InstructionTruncated));
+        //trailing bytes
+        let mut trailing = withdraw.clone();
+        trailing.push(0);
+        assert_eq!(PerpetualInstruction::unpack(&trailing), perp_err(PerpError::InstructionTrailingBytes));
+        assert_eq!(PerpetualInstruction::unpack(&[2, 255, 0]), perp_err(PerpError::InstructionTrailingBytes));
+        //symbol
+        assert_eq!(PerpetualInstruction::unpack(&[1, 0, 2, 0xff, 0xfe]), perp_err(PerpError::InvalidSymbol));
+        assert_eq!(PerpetualInstruction::unpack(&[1, 0, 0]), perp_err(PerpError::EmptySymbol));
+        let mut long = vec![1, 0, MAX_SYMBOL_LEN as u8 + 1];
+        long.extend(vec![b'A'; MAX_SYMBOL_LEN + 1]);
+        assert_eq!(PerpetualInstruction::unpack(&long), perp_err(PerpError::SymbolTooLong));
+        //pack rejects the symbol, build the envelope by hand
+        let mut versioned = vec![VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION];
+        PerpetualInstruction::SetRateLimit {
+            account_type: 0,
+            symbol: "A".repeat(MAX_SYMBOL_LEN + 1),
+            window: 1,
+            max_amount: 1,
+        }
+        .serialize(&mut versioned)
+        .unwrap();
+        assert_eq!(PerpetualInstruction::unpack(&versioned), perp_err(PerpError::SymbolTooLong));
+        let mut versioned = vec![VERSIONED_INSTRUCTION_TAG, INSTRUCTION_VERSION];
+        PerpetualInstruction::Deposit { account_type: 0, symbol: String::new(), amount: 1 }.serialize(&mut versioned).unwrap();
+        assert_eq!(PerpetualInstruction::unpack(&versioned), perp_err(PerpError::EmptySymbol));
+        //version
+        for version in [0, INSTRUCTION_VERSION + 1, u8::MAX] {
+            assert_eq!(
+                PerpetualInstruction::unpack(&[VERSIONED_INSTRUCTION_TAG, version, 0]),
+                perp_err(PerpError::UnsupportedInstructionVersion)
+            );
+        }
+        //unknown tag, borsh trailing bytes
+        assert_eq!(PerpetualInstruction::unpack(&[5]), Err(InvalidInstructionData));
+        assert_eq!(PerpetualInstruction::unpack(&[]), Err(InvalidInstructionData));
+        let mut borsh_trailing = PerpetualInstruction::AcceptAdmin.pack().unwrap();
+        borsh_trailing.push(0);
+        assert_eq!(PerpetualInstruction::unpack(&borsh_trailing), Err(InvalidInstructionData));
+    }
+}
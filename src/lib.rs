@@ -3,7 +3,34 @@
 pub mod error;
 pub mod instruction;
 pub mod processor;
+pub mod signature;
 pub mod state;
 
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
+
+#[cfg(test)]
+mod test_utils;
+
+use solana_program::pubkey::Pubkey;
+
+/// Seed of the perpetual account and the prefix of every user account
+pub const PERPETUAL_SEED: &[u8] = b"perpetual";
+
+/// Derives the perpetual account(config) address and bump
+pub fn find_perpetual_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PERPETUAL_SEED], program_id)
+}
+
+/// Derives the perpetual user account address and bump of `user`
+pub fn find_account_address(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PERPETUAL_SEED, user.as_ref()], program_id)
+}
+
+/// Seed prefix of the token config accounts
+pub const TOKEN_SEED: &[u8] = b"token";
+
+/// Derives the token config address and bump of `account_type` and `symbol`
+pub fn find_token_address(program_id: &Pubkey, account_type: u8, symbol: &str) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[TOKEN_SEED, &[account_type], symbol.as_bytes()], program_id)
+}
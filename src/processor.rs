@@ -3,8 +3,6 @@ use solana_program::{
     entrypoint::ProgramResult,
     system_instruction,
     msg,
-    secp256k1_recover,
-    keccak,
     log::sol_log_compute_units,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
@@ -12,18 +10,34 @@ use solana_program::{
     pubkey::Pubkey,
     sysvar::{rent::Rent, clock::Clock, Sysvar},
 };
-use spl_token::state::Account as TokenAccount;
+use spl_token::state::{Account as TokenAccount, Mint};
 use spl_token::ID as TokenProgramId;
 //use std::str; //convert::TryInto,
 use crate::{
     error::PerpError, 
-    instruction::PerpetualInstruction,
-    state::{Perpetual, Account, TypeSymbol, MintProgram},
-
+    instruction::{validate_symbol, BatchWithdrawEntry, ConfigChange, PerpetualInstruction, RecoverableSignature},
+    signature::{recover_signer, CancelWithdrawMessage, SignatureScheme, WithdrawMessage},
+    state::{
+        Perpetual, Account, TypeSymbol, TokenConfig, RateLimit, Role, LegacyAccount, LegacyMintProgram, LegacyPerpetual,
+        is_legacy, CONFIG_TIMELOCK, MAX_GUARDIANS, MAX_SIGNERS, PAUSE_ALL, PAUSE_DEPOSIT, PAUSE_WITHDRAW,
+        ACCOUNT_BYTES, PERPETUAL_BYTES, TOKEN_CONFIG_BYTES,
+    },
+    PERPETUAL_SEED, TOKEN_SEED,
 };
 
 
 
+//fields of one withdraw, signed by the secp256k1 signer
+#[derive(Clone, Copy)]
+struct WithdrawRequest<'a> {
+    account_type: u8,
+    symbol: &'a str,
+    amount: u64,
+    withdrawid: u64,
+    timestamp: u64,
+    signatures: &'a [RecoverableSignature],
+}
+
 pub struct Processor;
 impl Processor {
     pub fn process(
@@ -38,17 +52,20 @@ impl Processor {
                 secp256k1_pubkey,
                 gateway,
                 admin,
+                bump_seed,
             } => {
-                Self::process_init_perpetual(accounts, secp256k1_pubkey, gateway, admin, program_id)
+                Self::process_init_perpetual(accounts, secp256k1_pubkey, gateway, admin, bump_seed, program_id)
             }
             PerpetualInstruction::SetTokenMap {
                 account_type,
                 symbol,
             } => {
-                Self::process_set_tokenmap(accounts, account_type, symbol)
+                Self::process_set_tokenmap(accounts, account_type, symbol, program_id)
             }
-            PerpetualInstruction::InitAccount => {
-                Self::process_init_account(accounts, program_id)
+            PerpetualInstruction::InitAccount {
+                bump_seed,
+            } => {
+                Self::process_init_account(accounts, bump_seed, program_id)
             }
             PerpetualInstruction::Deposit {
                 account_type,
@@ -66,7 +83,131 @@ impl Processor {
                 recovery_id,
                 signature,
             } => {
-                Self::process_withdraw(accounts, account_type, symbol, amount, withdrawid, timestamp, recovery_id, signature, program_id)
+                let signatures = [RecoverableSignature{recovery_id, signature}];
+                let request = WithdrawRequest {
+                    account_type,
+                    symbol: &symbol,
+                    amount,
+                    withdrawid,
+                    timestamp,
+                    signatures: &signatures,
+                };
+                Self::process_withdraw(accounts, &request, false, program_id)
+            }
+            PerpetualInstruction::ProposeAdmin {
+                new_admin,
+            } => {
+                Self::process_propose_admin(accounts, new_admin, program_id)
+            }
+            PerpetualInstruction::AcceptAdmin => {
+                Self::process_accept_admin(accounts, program_id)
+            }
+            PerpetualInstruction::CancelAdminProposal => {
+                Self::process_propose_admin(accounts, Pubkey::default(), program_id)
+            }
+            PerpetualInstruction::QueueConfigChange {
+                change,
+            } => {
+                Self::process_queue_config_change(accounts, change, program_id)
+            }
+            PerpetualInstruction::CancelConfigChange => {
+                Self::process_cancel_config_change(accounts, program_id)
+            }
+            PerpetualInstruction::WithdrawMultisig {
+                account_type,
+                symbol,
+                amount,
+                withdrawid,
+                timestamp,
+                signatures,
+            } => {
+                let request = WithdrawRequest {
+                    account_type,
+                    symbol: &symbol,
+                    amount,
+                    withdrawid,
+                    timestamp,
+                    signatures: &signatures,
+                };
+                Self::process_withdraw(accounts, &request, false, program_id)
+            }
+            PerpetualInstruction::SetMultisigAmount {
+                account_type,
+                symbol,
+                amount,
+            } => {
+                Self::process_set_multisig_amount(accounts, account_type, symbol, amount, program_id)
+            }
+            PerpetualInstruction::SetSignatureScheme {
+                scheme,
+            } => {
+                Self::process_set_signature_scheme(accounts, scheme, program_id)
+            }
+            PerpetualInstruction::CancelWithdrawId {
+                account_type,
+                withdrawid,
+                signature,
+            } => {
+                Self::process_cancel_withdraw_id(accounts, account_type, withdrawid, signature, program_id)
+            }
+            PerpetualInstruction::BatchWithdraw {
+                entries,
+            } => {
+                Self::process_batch_withdraw(accounts, &entries, program_id)
+            }
+            PerpetualInstruction::RelayedWithdraw {
+                account_type,
+                symbol,
+                amount,
+                withdrawid,
+                timestamp,
+                signatures,
+            } => {
+                let request = WithdrawRequest {
+                    account_type,
+                    symbol: &symbol,
+                    amount,
+                    withdrawid,
+                    timestamp,
+                    signatures: &signatures,
+                };
+                Self::process_withdraw(accounts, &request, true, program_id)
+            }
+            PerpetualInstruction::SetPause {
+                token,
+                flags,
+            } => {
+                Self::process_set_pause(accounts, token, flags, program_id)
+            }
+            PerpetualInstruction::SetGuardians {
+                guardians,
+            } => {
+                Self::process_set_guardians(accounts, guardians, program_id)
+            }
+            PerpetualInstruction::SetRateLimit {
+                account_type,
+                symbol,
+                window,
+                max_amount,
+            } => {
+                Self::process_set_rate_limit(accounts, account_type, symbol, window, max_amount, program_id)
+            }
+            PerpetualInstruction::SetWithdrawLimits {
+                account_type,
+                symbol,
+                max_amount,
+                user_daily_cap,
+                cosign_amount,
+            } => {
+                Self::process_set_withdraw_limits(accounts, account_type, symbol, max_amount, user_daily_cap, cosign_amount, program_id)
+            }
+            PerpetualInstruction::SetMaxWithdrawValidity {
+                validity,
+            } => {
+                Self::process_set_max_withdraw_validity(accounts, validity, program_id)
+            }
+            PerpetualInstruction::MigrateAccount => {
+                Self::process_migrate_account(accounts, program_id)
             }
         }
     }
@@ -76,6 +217,7 @@ impl Processor {
         secp256k1_pubkey: [u8; 64],
         gateway: Pubkey,
         admin: Pubkey,
+        bump_seed: Option<u8>,
         program_id: &Pubkey,
     ) -> ProgramResult {
         //msg!("process instuction 0");
@@ -91,8 +233,9 @@ impl Processor {
         //2.perpetual account
         let perpetual_info = next_account_info(account_info_iter)?;
         if perpetual_info.data_is_empty() {
-            let lamports_required = (Rent::get()?).minimum_balance(Perpetual::LEN);
-            let (_pda, bump_seed) = Pubkey::find_program_address(&[b"perpetual"], program_id);
+            //check address is the canonical pda, no bump stored yet
+            let bump_seed = Self::check_pda(perpetual_info.key, &[PERPETUAL_SEED], bump_seed, program_id)?;
+            let lamports_required = (Rent::get()?).minimum_balance(PERPETUAL_BYTES);
             //3.system account
             let system_account = next_account_info(account_info_iter)?;
             //create account with pda as publickey must be cpi, because signature needed
@@ -101,7 +244,7 @@ impl Processor {
                     admin_info.key,
                     perpetual_info.key,
                     lamports_required,
-                    Perpetual::LEN as u64,
+                    PERPETUAL_BYTES as u64,
                     program_id,
                 ),
                 &[
@@ -109,28 +252,38 @@ impl Processor {
                     perpetual_info.clone(),
                     system_account.clone(),
                 ],
-                &[&[&b"perpetual"[..], &[bump_seed]]],
+                &[&[PERPETUAL_SEED, &[bump_seed]]],
             )?;
-            let mut perpetual = Perpetual::unpack_unchecked(&perpetual_info.data.borrow())?;
-            perpetual.is_initialized = true;
-            perpetual.secp256k1_pubkey = secp256k1_pubkey;
-            perpetual.gateway = gateway;
-            perpetual.admin = admin;
-            perpetual.bump_seed = bump_seed;
+            let perpetual = Perpetual::new(secp256k1_pubkey, gateway, admin, bump_seed);
             msg!("Perpetual initial info:{:?}", perpetual);
-            Perpetual::pack(perpetual, &mut perpetual_info.data.borrow_mut())?;
+            Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
         } else {
-            //data unpack
-            let mut perpetual = Perpetual::unpack(&perpetual_info.data.borrow())?;
+            //data unpack, checks the pda with the stored bump
+            let mut perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+            if bump_seed.is_some_and(|bump_seed| bump_seed != perpetual.bump_seed) {
+                msg!("Perpetual incorrect bump:{:?} stored:{}", bump_seed, perpetual.bump_seed);
+                return Err(PerpError::InvalidPda.into());
+            }
             if perpetual.admin != *admin_info.key {
                 msg!("Perpetual incorrect admin:{:?}", perpetual.admin);
                 return Err(ProgramError::InvalidAccountData);
             }
-            perpetual.secp256k1_pubkey = secp256k1_pubkey;
-            perpetual.gateway = gateway;
-            perpetual.admin = admin;
+            //admin only changes by ProposeAdmin, AcceptAdmin
+            if perpetual.admin != admin {
+                msg!("Perpetual admin change needs ProposeAdmin:{}", admin);
+                return Err(PerpError::AdminTransferRequired.into());
+            }
+            //signer key and gateway are timelocked
+            let now_timestamp = Clock::get()?.unix_timestamp;
+            perpetual.apply_config_changes(now_timestamp);
+            if perpetual.secp256k1_pubkey != secp256k1_pubkey {
+                Self::queue_config_change(&mut perpetual, ConfigChange::Secp256k1Pubkey(secp256k1_pubkey), now_timestamp)?;
+            }
+            if perpetual.gateway != gateway {
+                Self::queue_config_change(&mut perpetual, ConfigChange::Gateway(gateway), now_timestamp)?;
+            }
             msg!("Perpetual info:{:?}", perpetual);
-            Perpetual::pack(perpetual, &mut perpetual_info.data.borrow_mut())?;
+            Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
         }
         //msg!("perpetual account initialize:{}", perpetual_info.key);
         Ok(())
@@ -140,6 +293,7 @@ impl Processor {
         accounts: &[AccountInfo],
         account_type: u8,
         symbol: String,
+        program_id: &Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
@@ -152,8 +306,7 @@ impl Processor {
 
         //2.perpetual account
         let perpetual_info = next_account_info(account_info_iter)?;
-        //data unpack
-        let mut perpetual = Perpetual::unpack(&perpetual_info.data.borrow())?;
+        let perpetual = Self::load_perpetual(perpetual_info, program_id)?;
         //check admin
         if *admin_info.key != perpetual.admin {
             msg!("Perpetual incorrect admin:{:?}", perpetual.admin);
@@ -162,6 +315,13 @@ impl Processor {
 
         //3.program token account
         let program_token_account_info = next_account_info(account_info_iter)?;
+
+        //4.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+        //check address is pda, one account per token
+        let token_seeds: &[&[u8]] = &[TOKEN_SEED, &[account_type], symbol.as_bytes()];
+        let bump_seed = Self::check_pda(token_config_info.key, token_seeds, None, program_id)?;
+
         //check account owner
         if *program_token_account_info.owner == TokenProgramId {
             //data unpack
@@ -171,19 +331,99 @@ impl Processor {
                 msg!("Perpetual incorrect owner:{:?}", token_info.owner);
                 return Err(ProgramError::InvalidAccountData);
             }
-            perpetual.token_map.insert(TypeSymbol{account_type, symbol}, MintProgram{mint: token_info.mint, program_token_account: *program_token_account_info.key});         
-        } else {
-            //remove
-            perpetual.token_map.remove(&TypeSymbol{account_type, symbol});
+            //5.system account
+            let system_account = next_account_info(account_info_iter)?;
+            //6.mint account
+            let mint_info = next_account_info(account_info_iter)?;
+            Self::write_token_config(
+                admin_info,
+                token_config_info,
+                system_account,
+                mint_info,
+                &token_info.mint,
+                program_token_account_info.key,
+                account_type,
+                symbol,
+                bump_seed,
+                program_id,
+            )?;
+        } else if token_config_info.owner == program_id {
+            //remove, close the token config account to the admin
+            Self::load_token_config(token_config_info, account_type, &symbol, program_id)?;
+            let lamports = token_config_info.lamports();
+            **admin_info.lamports.borrow_mut() = admin_info
+                .lamports()
+                .checked_add(lamports)
+                .ok_or(ProgramError::InvalidArgument)?;
+            **token_config_info.lamports.borrow_mut() = 0;
+            //closed, no data and back to the system program, as if never added
+            token_config_info.realloc(0, false)?;
+            token_config_info.assign(&solana_program::system_program::id());
+            msg!("Perpetual removed token type:{} symbol:{}", account_type, symbol);
+        }
+        Ok(())
+    }
+
+    //creates or updates the token config account of a token, the caller checks the pda
+    //and passes its canonical bump
+    #[allow(clippy::too_many_arguments)]
+    fn write_token_config<'a>(
+        admin_info: &AccountInfo<'a>,
+        token_config_info: &AccountInfo<'a>,
+        system_account: &AccountInfo<'a>,
+        mint_info: &AccountInfo<'a>,
+        mint: &Pubkey,
+        program_token_account: &Pubkey,
+        account_type: u8,
+        symbol: String,
+        bump_seed: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        //the symbol region is MAX_SYMBOL_LEN, a longer one would not match its pda seeds
+        if let Err(e) = validate_symbol(&symbol) {
+            msg!("Perpetual invalid symbol:{:?}", symbol);
+            return Err(e.into());
+        }
+        //check mint
+        if mint_info.key != mint || *mint_info.owner != TokenProgramId {
+            msg!("Perpetual incorrect mint:{}", mint_info.key);
+            return Err(ProgramError::InvalidAccountData);
         }
-        msg!("Perpetual info:{:?}", perpetual);
-        Perpetual::pack(perpetual, &mut perpetual_info.data.borrow_mut())?;
+        let mint_data = Mint::unpack(&mint_info.try_borrow_data()?)?;
+
+        let mut token_config = if token_config_info.data_is_empty() {
+            let lamports_required = (Rent::get()?).minimum_balance(TOKEN_CONFIG_BYTES);
+            invoke_signed(
+                &system_instruction::create_account(
+                    admin_info.key,
+                    token_config_info.key,
+                    lamports_required,
+                    TOKEN_CONFIG_BYTES as u64,
+                    program_id,
+                ),
+                &[
+                    admin_info.clone(),
+                    token_config_info.clone(),
+                    system_account.clone(),
+                ],
+                &[&[TOKEN_SEED, &[account_type], symbol.as_bytes(), &[bump_seed]]],
+            )?;
+            TokenConfig::new(account_type, symbol, bump_seed)?
+        } else {
+            //keep the limits and flags of an updated token
+            Self::load_token_config(token_config_info, account_type, &symbol, program_id)?
+        };
+        token_config.mint = *mint;
+        token_config.program_token_account = *program_token_account;
+        token_config.decimals = mint_data.decimals;
+        msg!("Perpetual token config:{:?}", token_config);
+        token_config.store(&mut token_config_info.data.borrow_mut())?;
         Ok(())
     }
 
     fn process_init_account(
         accounts: &[AccountInfo],
-        //user: Pubkey,
+        bump_seed: Option<u8>,
         program_id: &Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -198,15 +438,16 @@ impl Processor {
         //2.perpetual user account
         let account_info = next_account_info(account_info_iter)?;
         if account_info.data_is_empty() {
-            let lamports_required = (Rent::get()?).minimum_balance(Account::LEN);
-            let (_pda, bump_seed) = Pubkey::find_program_address(&[b"perpetual", user_info.key.as_ref()], program_id);
+            //check address is the canonical pda, one account per user
+            let bump_seed = Self::check_pda(account_info.key, &[PERPETUAL_SEED, user_info.key.as_ref()], bump_seed, program_id)?;
+            let lamports_required = (Rent::get()?).minimum_balance(ACCOUNT_BYTES);
             let system_account = next_account_info(account_info_iter)?;
             invoke_signed(
                 &system_instruction::create_account(
                     user_info.key,
                     account_info.key,
                     lamports_required,
-                    Account::LEN as u64,
+                    ACCOUNT_BYTES as u64,
                     program_id,
                 ),
                 &[
@@ -214,17 +455,15 @@ impl Processor {
                     account_info.clone(),
                     system_account.clone(),
                 ],
-                &[&[&b"perpetual"[..], user_info.key.as_ref(), &[bump_seed]]],
+                &[&[PERPETUAL_SEED, user_info.key.as_ref(), &[bump_seed]]],
             )?;
-            let mut account = Account::unpack_unchecked(&account_info.data.borrow())?;
-            account.is_initialized = true;
-            account.user = *user_info.key;
+            let account = Account::new(*user_info.key, bump_seed);
             msg!("Perpetual account:{:?}", account);
-            Account::pack(account, &mut account_info.data.borrow_mut())?;
+            account.store(&mut account_info.data.borrow_mut())?;
             Ok(())
         } else {
-            return Err(ProgramError::AccountAlreadyInitialized);
-        }   
+            Err(ProgramError::AccountAlreadyInitialized)
+        }
     }
 
     fn process_deposit(
@@ -259,12 +498,7 @@ impl Processor {
         //4.perpetual user account
         let account_info = next_account_info(account_info_iter)?;
         //check owner is programid
-        if account_info.owner != program_id {
-            msg!("Perpetual incorrect user account:{}", account_info.owner);
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        //unpack
-        let account = Account::unpack(&account_info.data.borrow())?;
+        let account = Self::load_user_account(account_info, program_id)?;
         //check account's user is the signer
         if account.user != *token_owner_info.key {
             msg!("Perpetual incorrect user:{}", account.user);
@@ -273,31 +507,32 @@ impl Processor {
         
         //5.perpetual account
         let admin_info = next_account_info(account_info_iter)?;
-        //unpack
-        let admin_data = Perpetual::unpack(&admin_info.try_borrow_data()?)?;
-        //check owner is programid
-        if admin_info.owner != program_id {
-            msg!("Perpetual incorrect perpetual account:{}", admin_info.owner);
+        let admin_data = Self::load_perpetual(admin_info, program_id)?;
+
+        //6.token program account
+        let token_program_info = next_account_info(account_info_iter)?;
+        //check token programId
+        if *token_program_info.key != TokenProgramId {
             return Err(ProgramError::IncorrectProgramId);
         }
-        let mint_program = admin_data.token_map[&TypeSymbol{account_type, symbol:symbol.clone()}];
+
+        //7.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+        let token_config = Self::load_token_config(token_config_info, account_type, &symbol, program_id)?;
+        if admin_data.is_paused(token_config.pause_flags, PAUSE_DEPOSIT) {
+            msg!("Perpetual deposit paused type:{} symbol:{}", account_type, symbol);
+            return Err(PerpError::Paused.into());
+        }
         //check mint is token account's mint
-        if token_info.mint != mint_program.mint {
-            msg!("Perpetual incorrect mint:{}", mint_program.mint);
+        if token_info.mint != token_config.mint {
+            msg!("Perpetual incorrect mint:{}", token_config.mint);
             return Err(ProgramError::InvalidAccountData);
         }
         //check address is program token account address
-        if mint_program.program_token_account != *program_token_account_info.key {
-            msg!("Perpetual incorrect program token account:{}", mint_program.program_token_account);
+        if token_config.program_token_account != *program_token_account_info.key {
+            msg!("Perpetual incorrect program token account:{}", token_config.program_token_account);
             return Err(ProgramError::InvalidAccountData);
         }
-
-        //6.token program account
-        let token_program_info = next_account_info(account_info_iter)?;
-        //check token programId
-        if *token_program_info.key != TokenProgramId {
-            return Err(ProgramError::IncorrectProgramId);
-        }
         
         msg!("Perpetual deposit CPI");
         //transfer token from user_token_account_info to program_token_account_info
@@ -306,7 +541,7 @@ impl Processor {
             user_token_account_info.key,
             program_token_account_info.key,
             token_owner_info.key,
-            &[&token_owner_info.key],
+            &[token_owner_info.key],
             amount,
         )?;
         invoke(
@@ -327,20 +562,15 @@ impl Processor {
 
     fn process_withdraw(
         accounts: &[AccountInfo],
-        account_type: u8,
-        symbol: String,
-        amount: u64,
-        withdrawid: u64,
-        timestamp: u64,
-        recovery_id: u8,
-        signature: [u8;64],
+        request: &WithdrawRequest,
+        relayed: bool,
         program_id: &Pubkey,
     ) -> ProgramResult {
         sol_log_compute_units();
         
         let account_info_iter = &mut accounts.iter();
 
-        //1.signer account
+        //1.signer account, the user or the gateway when relayed
         let signer_info = next_account_info(account_info_iter)?;
         //check signer
         if !signer_info.is_signer {
@@ -349,106 +579,247 @@ impl Processor {
 
         //2.user token account
         let dest_token_account_info = next_account_info(account_info_iter)?;
-        //unpack
-        let dest_token_account_data = TokenAccount::unpack(&dest_token_account_info.try_borrow_data()?)?;
-        //check owner is signer
-        if *signer_info.key != dest_token_account_data.owner {
-            msg!("Perpetual incorrect token owner:{}", dest_token_account_data.owner);
-            return Err(ProgramError::InvalidAccountData);
-        }
-        
         //3.program token account
         let program_token_account_info = next_account_info(account_info_iter)?;
-
         //4.perpetual user account
         let account_info = next_account_info(account_info_iter)?;
-        //unpack
-        let mut account = Account::unpack(&account_info.data.borrow())?;
-        //check user is signer
-        if account.user != *signer_info.key {
-            msg!("Perpetual incorrect user:{}", account.user);
-            return Err(ProgramError::InvalidAccountData);
-        }
 
         //5.perpetual account
         let admin_info = next_account_info(account_info_iter)?;
-        if admin_info.owner != program_id {
-            msg!("Perpetual incorrect perpetual account:{}", admin_info.owner);
+        let admin_data = Self::load_perpetual(admin_info, program_id)?;
+
+        //6.token program account
+        let token_program_info = next_account_info(account_info_iter)?;
+        //check token programId
+        if *token_program_info.key != TokenProgramId {
             return Err(ProgramError::IncorrectProgramId);
         }
-        //unpack
-        let admin_data = Perpetual::unpack(&admin_info.try_borrow_data()?)?;
-        let mint_program = admin_data.token_map[&TypeSymbol{account_type, symbol: symbol.clone()}];
+
+        //7.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        //relayed: gateway pays the fee, funds still only go to the account user
+        let user = if relayed {
+            Self::check_role(&admin_data, signer_info, &[Role::Gateway], now_timestamp)?;
+            None
+        } else {
+            Some(signer_info.key)
+        };
+        //8.optional admin or gateway co-signer, the relaying gateway already is
+        let cosigned = match account_info_iter.next() {
+            Some(cosigner_info) => {
+                Self::check_role(&admin_data, cosigner_info, &[Role::Admin, Role::Gateway], now_timestamp)?;
+                true
+            }
+            None => relayed,
+        };
+        Self::execute_withdraw(
+            program_id,
+            &admin_data,
+            admin_info,
+            token_program_info,
+            dest_token_account_info,
+            program_token_account_info,
+            account_info,
+            token_config_info,
+            user,
+            cosigned,
+            request,
+            now_timestamp,
+        )
+    }
+
+    fn process_batch_withdraw(
+        accounts: &[AccountInfo],
+        entries: &[BatchWithdrawEntry],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.gateway signer account
+        let gateway_info = next_account_info(account_info_iter)?;
+
+        //2.perpetual account, unpacked once for the batch
+        let admin_info = next_account_info(account_info_iter)?;
+        let admin_data = Self::load_perpetual(admin_info, program_id)?;
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        Self::check_role(&admin_data, gateway_info, &[Role::Gateway], now_timestamp)?;
+
+        //3.token program account
+        let token_program_info = next_account_info(account_info_iter)?;
+        if *token_program_info.key != TokenProgramId {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        //4..remaining accounts, 4 per entry
+        let remaining = account_info_iter.as_slice();
+        if remaining.len() != entries.len() * 4 {
+            msg!("Perpetual batch accounts:{} entries:{}", remaining.len(), entries.len());
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        //any failed entry fails the transaction, all or nothing
+        for (index, (entry, entry_accounts)) in entries.iter().zip(remaining.chunks_exact(4)).enumerate() {
+            msg!("Perpetual batch withdraw entry:{}", index);
+            sol_log_compute_units();
+            let request = WithdrawRequest {
+                account_type: entry.account_type,
+                symbol: &entry.symbol,
+                amount: entry.amount,
+                withdrawid: entry.withdrawid,
+                timestamp: entry.timestamp,
+                signatures: &entry.signatures,
+            };
+            Self::execute_withdraw(
+                program_id,
+                &admin_data,
+                admin_info,
+                token_program_info,
+                &entry_accounts[0],
+                &entry_accounts[1],
+                &entry_accounts[2],
+                &entry_accounts[3],
+                None,
+                true,
+                &request,
+                now_timestamp,
+            )?;
+        }
+        sol_log_compute_units();
+        Ok(())
+    }
+
+    //checks and transfer of one withdraw, perpetual account and token program are checked by the caller
+    //user: the transaction signer that must be the account user, none when relayed
+    //cosigned: the admin or gateway signed the transaction
+    #[allow(clippy::too_many_arguments)]
+    fn execute_withdraw<'a>(
+        program_id: &Pubkey,
+        admin_data: &Perpetual,
+        admin_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        dest_token_account_info: &AccountInfo<'a>,
+        program_token_account_info: &AccountInfo<'a>,
+        account_info: &AccountInfo<'a>,
+        token_config_info: &AccountInfo<'a>,
+        user: Option<&Pubkey>,
+        cosigned: bool,
+        request: &WithdrawRequest,
+        now_timestamp: i64,
+    ) -> ProgramResult {
+        let WithdrawRequest {
+            account_type,
+            symbol,
+            amount,
+            withdrawid,
+            timestamp,
+            signatures,
+        } = *request;
+
+        //perpetual user account
+        let mut account = Self::load_user_account(account_info, program_id)?;
+        //check user is signer
+        if let Some(user) = user {
+            if account.user != *user {
+                msg!("Perpetual incorrect user:{}", account.user);
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
+
+        //user token account
+        let dest_token_account_data = TokenAccount::unpack(&dest_token_account_info.try_borrow_data()?)?;
+        //check token owner is the user, funds only go to the user
+        if account.user != dest_token_account_data.owner {
+            msg!("Perpetual incorrect token owner:{}", dest_token_account_data.owner);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut token_config = Self::load_token_config(token_config_info, account_type, symbol, program_id)?;
+        if admin_data.is_paused(token_config.pause_flags, PAUSE_WITHDRAW) {
+            msg!("Perpetual withdraw paused type:{} symbol:{}", account_type, symbol);
+            return Err(PerpError::Paused.into());
+        }
         //check mint is token account's mint
-        if dest_token_account_data.mint != mint_program.mint {
-            msg!("Perpetual incorrect mint:{}", mint_program.mint);
+        if dest_token_account_data.mint != token_config.mint {
+            msg!("Perpetual incorrect mint:{}", token_config.mint);
             return Err(ProgramError::InvalidAccountData);
         }
         //check address is program token account address
-        if mint_program.program_token_account != *program_token_account_info.key {
-            msg!("Perpetual incorrect program token account:{}", mint_program.program_token_account);
+        if token_config.program_token_account != *program_token_account_info.key {
+            msg!("Perpetual incorrect program token account:{}", token_config.program_token_account);
             return Err(ProgramError::InvalidAccountData);
         }
 
-        //6.token program account
-        let token_program_info = next_account_info(account_info_iter)?;
-        //check token programId
-        if *token_program_info.key != TokenProgramId {
-            return Err(ProgramError::IncorrectProgramId);
+        //check amount limits
+        if token_config.max_withdraw_amount != 0 && amount > token_config.max_withdraw_amount {
+            msg!("Perpetual withdraw amount:{} max:{}", amount, token_config.max_withdraw_amount);
+            return Err(PerpError::WithdrawAmountTooLarge.into());
+        }
+        if token_config.cosign_amount != 0 && amount >= token_config.cosign_amount && !cosigned {
+            msg!("Perpetual withdraw amount:{} cosign from:{}", amount, token_config.cosign_amount);
+            return Err(PerpError::CosignatureRequired.into());
         }
 
-        //check withdrawid
-        let account_withdraw_id: u64 = match account.withdraw_id.get(&account_type) {
-            Some(&wdid) => wdid,
-            None => 0,
-        };
-        if account_withdraw_id >= withdrawid {
-            msg!("Perpetual incorrect withdrawId:{}", account_withdraw_id);
-            return Err(PerpError::WithdrawIdFail.into());
+        //check withdrawid, any order inside the window, once
+        if let Err(e) = account.use_withdraw_id(account_type, withdrawid) {
+            msg!("Perpetual incorrect withdrawId:{} window:{:?}", withdrawid, account.withdraw_id.get(&account_type));
+            return Err(e.into());
         }
 
-        //check timestamp
-        let now_timestamp = Clock::get()?.unix_timestamp;
-        if timestamp <= now_timestamp.try_into().unwrap() {
-            msg!("Perpetual incorrect timestamp:{}", now_timestamp);
-            return Err(ProgramError::InvalidInstructionData);
+        //check timestamp, in (now, now + max_withdraw_validity]
+        let now = now_timestamp as i128;
+        if (timestamp as i128) <= now {
+            msg!("Perpetual withdraw expired timestamp:{} now:{}", timestamp, now_timestamp);
+            return Err(PerpError::WithdrawExpired.into());
+        }
+        let max_withdraw_validity = admin_data.max_withdraw_validity;
+        if max_withdraw_validity != 0 && timestamp as i128 > now + max_withdraw_validity as i128 {
+            msg!("Perpetual withdraw timestamp:{} now:{} max validity:{}", timestamp, now_timestamp, max_withdraw_validity);
+            return Err(PerpError::WithdrawValidityTooLong.into());
         }
         
         //check signature
-        //maybe vec if length
-        //msg!("user_account:{}", user_account);
-        let user_account_string = account.user.to_string();
-        let user_account_bytes = user_account_string.as_bytes();
-        //msg!("user_account_bytes:{}", user_account_bytes.len());
-        let symbol_bytes = symbol.as_bytes();
-        let mut dst_data = vec![];
-        let mut data_offset = user_account_bytes.len();
-        dst_data.resize(
-            data_offset
-            .saturating_add(symbol.len())
-            .saturating_add(1+8+8+8),
-        0,
-        );
-        dst_data[..data_offset].copy_from_slice(user_account_bytes);
-        dst_data[data_offset..data_offset+1].copy_from_slice(&account_type.to_be_bytes());
-        data_offset = data_offset + 1;
-        dst_data[data_offset..data_offset+symbol.len()].copy_from_slice(symbol_bytes);
-        data_offset = data_offset + symbol.len();
-        dst_data[data_offset..data_offset+8].copy_from_slice(&amount.to_be_bytes());
-        data_offset = data_offset + 8;
-        dst_data[data_offset..data_offset+8].copy_from_slice(&withdrawid.to_be_bytes());
-        data_offset = data_offset + 8;
-        dst_data[data_offset..data_offset+8].copy_from_slice(&timestamp.to_be_bytes());
-        //msg!("dst_data:{:?}", dst_data);
-        let hash = keccak::hash(&dst_data); //may need try_into()
-        //msg!("hash:{:?} recovery_id:{} signature:{:?}", hash.to_bytes(), recovery_id, signature);
-        //check signature
-        let pubkey_secp256k1 = secp256k1_recover::secp256k1_recover(&hash.to_bytes(), recovery_id, &signature).unwrap();
-        //msg!("recovery pubkey_secp256k1:{:?}", pubkey_secp256k1.to_bytes());
-        //msg!("input pubkey_secp256k1:{:?}", admin_data.secp256k1_pubkey);
-        if pubkey_secp256k1.to_bytes() != admin_data.secp256k1_pubkey {
-            msg!("Perpetual signature mismatch");
-            return Err(PerpError::SignatureMismatch.into());
+        let hash = WithdrawMessage {
+            program_id,
+            perpetual: admin_info.key,
+            destination: dest_token_account_info.key,
+            user: &account.user,
+            account_type,
+            symbol,
+            amount,
+            withdrawid,
+            timestamp,
+        }
+        .hash_with(admin_data.signature_scheme);
+        //check signature, the signer set from multisig_amount
+        let multisig = token_config.multisig_amount != 0 && amount >= token_config.multisig_amount;
+        if multisig || signatures.len() != 1 {
+            Self::check_multisig(admin_data, &hash, signatures, now_timestamp)?;
+        } else {
+            let RecoverableSignature{recovery_id, signature} = signatures[0];
+            let pubkey_secp256k1 = recover_signer(&hash, recovery_id, &signature)?;
+            //msg!("recovery pubkey_secp256k1:{:?}", pubkey_secp256k1);
+            //msg!("input pubkey_secp256k1:{:?}", admin_data.secp256k1_pubkey);
+            //a queued key is only used after its delay
+            if pubkey_secp256k1 != admin_data.active_secp256k1_pubkey(now_timestamp) {
+                msg!("Perpetual signature mismatch");
+                return Err(PerpError::SignatureMismatch.into());
+            }
+        }
+
+        //check rate limit, after the signature so a bad request doesn't count
+        if let Err(e) = token_config.rate_limit.consume(amount, now_timestamp) {
+            msg!("Perpetual rate limited type:{} symbol:{} amount:{} limit:{:?}",
+                  account_type, symbol, amount, token_config.rate_limit);
+            return Err(e.into());
+        }
+        let token = token_config.token();
+        if token_config.user_daily_cap != 0 {
+            if let Err(e) = account.use_daily_cap(&token, amount, token_config.user_daily_cap, now_timestamp) {
+                msg!("Perpetual daily cap type:{} symbol:{} amount:{} withdrawn:{:?}",
+                      account_type, symbol, amount, account.daily_withdrawn.get(&token));
+                return Err(e.into());
+            }
         }
 
         //cpi with pda
@@ -457,8 +828,8 @@ impl Processor {
             token_program_info.key,
             program_token_account_info.key,
             dest_token_account_info.key,
-            &admin_info.key,
-            &[&admin_info.key],
+            admin_info.key,
+            &[admin_info.key],
             amount,
         )?;
         invoke_signed(
@@ -469,18 +840,1999 @@ impl Processor {
                 admin_info.clone(),
                 token_program_info.clone(),
             ],
-            &[&[&b"perpetual"[..], &[admin_data.bump_seed]]],
+            &[&[PERPETUAL_SEED, &[admin_data.bump_seed]]],
         )?;
 
         //update account
-        account.withdraw_id.insert(account_type, withdrawid);
         msg!("Perpetual user account:{:?}", account);
-        Account::pack(account, &mut account_info.data.borrow_mut())?;
         msg!("Perpetual withdraw account:{} type:{} symbol:{} amount:{} withdrawid:{}", 
-              signer_info.key, account_type, symbol, amount, withdrawid);
+              account.user, account_type, symbol, amount, withdrawid);
+        account.store(&mut account_info.data.borrow_mut())?;
+        token_config.store(&mut token_config_info.data.borrow_mut())?;
         Ok(())
+    }
 
+    //M-of-N: keys in the active signer set, strictly ascending so no key counts twice
+    fn check_multisig(
+        perpetual: &Perpetual,
+        hash: &[u8; 32],
+        signatures: &[RecoverableSignature],
+        now_timestamp: i64,
+    ) -> ProgramResult {
+        let (signers, threshold) = perpetual.active_signers(now_timestamp);
+        if threshold == 0 || signatures.len() < threshold as usize || signatures.len() > signers.len() {
+            msg!("Perpetual signatures:{} threshold:{}", signatures.len(), threshold);
+            return Err(PerpError::ThresholdNotMet.into());
+        }
+        let mut last_key: Option<[u8; 64]> = None;
+        for RecoverableSignature{recovery_id, signature} in signatures {
+            let key = recover_signer(hash, *recovery_id, signature)?;
+            if !signers.contains(&key) {
+                msg!("Perpetual signer not in set");
+                return Err(PerpError::SignerNotInSet.into());
+            }
+            if let Some(last_key) = last_key {
+                if key <= last_key {
+                    msg!("Perpetual signers not ascending");
+                    return Err(PerpError::SignersNotAscending.into());
+                }
+            }
+            last_key = Some(key);
+        }
+        Ok(())
     }
 
-}
+    fn process_set_multisig_amount(
+        accounts: &[AccountInfo],
+        account_type: u8,
+        symbol: String,
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+        //3.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+        let mut token_config = Self::load_token_config(token_config_info, account_type, &symbol, program_id)?;
+
+        token_config.multisig_amount = amount;
+        msg!("Perpetual multisig amount type:{} symbol:{} amount:{}", account_type, symbol, amount);
+        token_config.store(&mut token_config_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_set_signature_scheme(
+        accounts: &[AccountInfo],
+        scheme: SignatureScheme,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        perpetual.signature_scheme = scheme;
+        msg!("Perpetual signature scheme:{:?}", scheme);
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_cancel_withdraw_id(
+        accounts: &[AccountInfo],
+        account_type: u8,
+        withdrawid: u64,
+        signature: Option<RecoverableSignature>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.authority account, admin, gateway or fee payer
+        let authority_info = next_account_info(account_info_iter)?;
+
+        //2.perpetual user account
+        let account_info = next_account_info(account_info_iter)?;
+        let mut account = Self::load_user_account(account_info, program_id)?;
+
+        //3.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+        let now_timestamp = Clock::get()?.unix_timestamp;
 
+        //check authority
+        match signature {
+            Some(RecoverableSignature{recovery_id, signature}) => {
+                let hash = CancelWithdrawMessage {
+                    program_id,
+                    perpetual: perpetual_info.key,
+                    user: &account.user,
+                    account_type,
+                    withdrawid,
+                }
+                .hash_with(perpetual.signature_scheme);
+                let pubkey_secp256k1 = recover_signer(&hash, recovery_id, &signature)?;
+                if pubkey_secp256k1 != perpetual.active_secp256k1_pubkey(now_timestamp) {
+                    msg!("Perpetual signature mismatch");
+                    return Err(PerpError::SignatureMismatch.into());
+                }
+            }
+            None => {
+                Self::check_role(&perpetual, authority_info, &[Role::Admin, Role::Gateway], now_timestamp)?;
+            }
+        }
+
+        //burn, only inside the window, a cancel never kills the ids below
+        if let Err(e) = account.cancel_withdraw_id(account_type, withdrawid) {
+            msg!("Perpetual incorrect withdrawId:{} window:{:?}", withdrawid, account.withdraw_id.get(&account_type));
+            return Err(e.into());
+        }
+        msg!("Perpetual cancel withdraw account:{} type:{} withdrawid:{}", account.user, account_type, withdrawid);
+        account.store(&mut account_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_set_pause(
+        accounts: &[AccountInfo],
+        token: Option<TypeSymbol>,
+        flags: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin or gateway signer account
+        let authority_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+        if flags & !PAUSE_ALL != 0 {
+            msg!("Perpetual incorrect pause flags:{}", flags);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        //3.token config account of a token
+        let token_config = match &token {
+            None => None,
+            Some(token) => {
+                let token_config_info = next_account_info(account_info_iter)?;
+                let token_config = Self::load_token_config(token_config_info, token.account_type, &token.symbol, program_id)?;
+                Some((token_config_info, token_config))
+            }
+        };
+        let current_flags = match &token_config {
+            None => perpetual.pause_flags,
+            Some((_, token_config)) => token_config.pause_flags,
+        };
+        //clearing a flag is admin only
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        let roles: &[Role] = if current_flags & !flags != 0 {
+            &[Role::Admin]
+        } else {
+            &[Role::Admin, Role::Gateway, Role::Guardian]
+        };
+        Self::check_role(&perpetual, authority_info, roles, now_timestamp)?;
+
+        msg!("Perpetual pause token:{:?} flags:{}", token, flags);
+        match token_config {
+            None => {
+                perpetual.pause_flags = flags;
+                Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+            }
+            Some((token_config_info, mut token_config)) => {
+                token_config.pause_flags = flags;
+                token_config.store(&mut token_config_info.data.borrow_mut())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn process_set_guardians(
+        accounts: &[AccountInfo],
+        guardians: Vec<Pubkey>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        if guardians.len() > MAX_GUARDIANS {
+            msg!("Perpetual too many guardians:{}", guardians.len());
+            return Err(ProgramError::InvalidArgument);
+        }
+        for (i, guardian) in guardians.iter().enumerate() {
+            if guardians[..i].contains(guardian) {
+                msg!("Perpetual duplicate guardian:{}", guardian);
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        msg!("Perpetual guardians:{:?}", guardians);
+        perpetual.guardians = guardians;
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_set_rate_limit(
+        accounts: &[AccountInfo],
+        account_type: u8,
+        symbol: String,
+        window: u64,
+        max_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+        //3.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+        let mut token_config = Self::load_token_config(token_config_info, account_type, &symbol, program_id)?;
+
+        let rate_limit = &mut token_config.rate_limit;
+        //a new window restarts the count
+        if rate_limit.window != window {
+            *rate_limit = RateLimit::default();
+            rate_limit.window = window;
+        }
+        rate_limit.max_amount = max_amount;
+
+        msg!("Perpetual rate limit type:{} symbol:{} window:{} max amount:{}", account_type, symbol, window, max_amount);
+        token_config.store(&mut token_config_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_set_withdraw_limits(
+        accounts: &[AccountInfo],
+        account_type: u8,
+        symbol: String,
+        max_amount: u64,
+        user_daily_cap: u64,
+        cosign_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+        //3.token config account
+        let token_config_info = next_account_info(account_info_iter)?;
+        let mut token_config = Self::load_token_config(token_config_info, account_type, &symbol, program_id)?;
+
+        token_config.max_withdraw_amount = max_amount;
+        token_config.user_daily_cap = user_daily_cap;
+        token_config.cosign_amount = cosign_amount;
+
+        msg!("Perpetual withdraw limits type:{} symbol:{} max amount:{} user daily cap:{} cosign amount:{}",
+              account_type, symbol, max_amount, user_daily_cap, cosign_amount);
+        token_config.store(&mut token_config_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_set_max_withdraw_validity(
+        accounts: &[AccountInfo],
+        validity: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        msg!("Perpetual max withdraw validity:{} old:{}", validity, perpetual.max_withdraw_validity);
+        perpetual.max_withdraw_validity = validity;
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_migrate_account(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.payer signer account
+        let payer_info = next_account_info(account_info_iter)?;
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        if perpetual_info.owner != program_id {
+            msg!("Perpetual incorrect perpetual account:{}", perpetual_info.owner);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        //3.system account
+        let system_account = next_account_info(account_info_iter)?;
+
+        if is_legacy(&perpetual_info.data.borrow()) {
+            let mut legacy = LegacyPerpetual::unpack(&perpetual_info.data.borrow())?;
+            Self::check_pda(perpetual_info.key, &[PERPETUAL_SEED], Some(legacy.bump_seed), program_id)?;
+            if *payer_info.key != legacy.admin {
+                msg!("Perpetual incorrect admin:{:?}", legacy.admin);
+                return Err(PerpError::IncorrectAdmin.into());
+            }
+            //4.. token config, mint of each token of the token map in order, as many as fit
+            //in the transaction, a token leaves the map once migrated
+            let tokens: Vec<(TypeSymbol, LegacyMintProgram)> =
+                legacy.token_map.iter().map(|(token, mint_program)| (token.clone(), *mint_program)).collect();
+            for (token, mint_program) in tokens {
+                //a symbol the token config can't hold is dropped, the admin adds it again with SetTokenMap
+                if validate_symbol(&token.symbol).is_err() {
+                    msg!("Perpetual dropped token type:{} symbol:{:?}", token.account_type, token.symbol);
+                    legacy.token_map.remove(&token);
+                    continue;
+                }
+                let token_config_info = match account_info_iter.next() {
+                    Some(token_config_info) => token_config_info,
+                    None => break,
+                };
+                let token_seeds: &[&[u8]] = &[TOKEN_SEED, &[token.account_type], token.symbol.as_bytes()];
+                let bump_seed = Self::check_pda(token_config_info.key, token_seeds, None, program_id)?;
+                let mint_info = next_account_info(account_info_iter)?;
+                Self::write_token_config(
+                    payer_info,
+                    token_config_info,
+                    system_account,
+                    mint_info,
+                    &mint_program.mint,
+                    &mint_program.program_token_account,
+                    token.account_type,
+                    token.symbol.clone(),
+                    bump_seed,
+                    program_id,
+                )?;
+                legacy.token_map.remove(&token);
+            }
+            if !legacy.token_map.is_empty() {
+                msg!("Perpetual migrating perpetual account, tokens left:{}", legacy.token_map.len());
+                legacy.pack(&mut perpetual_info.data.borrow_mut())?;
+                return Ok(());
+            }
+            let perpetual = legacy.migrate();
+            Self::resize_account(perpetual_info, payer_info, system_account, PERPETUAL_BYTES)?;
+            perpetual_info.data.borrow_mut().fill(0);
+            Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+            msg!("Perpetual migrated perpetual account");
+            return Ok(());
+        }
+        let perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+
+        //4.legacy user account
+        let account_info = next_account_info(account_info_iter)?;
+        if account_info.owner != program_id {
+            msg!("Perpetual incorrect user account:{}", account_info.owner);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if !is_legacy(&account_info.data.borrow()) {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        let legacy = LegacyAccount::unpack(&account_info.data.borrow())?;
+        let bump_seed = Self::check_pda(account_info.key, &[PERPETUAL_SEED, legacy.user.as_ref()], None, program_id)?;
+        if *payer_info.key != legacy.user && *payer_info.key != perpetual.admin {
+            msg!("Perpetual incorrect user:{}", legacy.user);
+            return Err(PerpError::Unauthorized.into());
+        }
+        let account = legacy.migrate(bump_seed)?;
+        Self::resize_account(account_info, payer_info, system_account, ACCOUNT_BYTES)?;
+        account_info.data.borrow_mut().fill(0);
+        account.store(&mut account_info.data.borrow_mut())?;
+        msg!("Perpetual migrated user account:{}", legacy.user);
+        Ok(())
+    }
+
+    //reallocs a program account to new_len, the payer tops up or gets back the rent difference
+    fn resize_account<'a>(
+        account_info: &AccountInfo<'a>,
+        payer_info: &AccountInfo<'a>,
+        system_account: &AccountInfo<'a>,
+        new_len: usize,
+    ) -> ProgramResult {
+        let rent_required = (Rent::get()?).minimum_balance(new_len);
+        let lamports = account_info.lamports();
+        if lamports < rent_required {
+            invoke(
+                &system_instruction::transfer(payer_info.key, account_info.key, rent_required - lamports),
+                &[
+                    payer_info.clone(),
+                    account_info.clone(),
+                    system_account.clone(),
+                ],
+            )?;
+        } else if lamports > rent_required {
+            //program owned, lamports move directly
+            **payer_info.lamports.borrow_mut() = payer_info
+                .lamports()
+                .checked_add(lamports - rent_required)
+                .ok_or(ProgramError::InvalidArgument)?;
+            **account_info.lamports.borrow_mut() = rent_required;
+        }
+        account_info.realloc(new_len, false)
+    }
+
+    fn process_propose_admin(
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        //default() cancels
+        perpetual.pending_admin = new_admin;
+        msg!("Perpetual pending admin:{}", new_admin);
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_accept_admin(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.pending admin signer account
+        let pending_admin_info = next_account_info(account_info_iter)?;
+        if !pending_admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+        if perpetual.pending_admin == Pubkey::default() {
+            return Err(PerpError::NoPendingAdmin.into());
+        }
+        if perpetual.pending_admin != *pending_admin_info.key {
+            msg!("Perpetual incorrect pending admin:{}", perpetual.pending_admin);
+            return Err(PerpError::IncorrectAdmin.into());
+        }
+
+        perpetual.admin = perpetual.pending_admin;
+        perpetual.pending_admin = Pubkey::default();
+        msg!("Perpetual admin:{}", perpetual.admin);
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_queue_config_change(
+        accounts: &[AccountInfo],
+        change: ConfigChange,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        perpetual.apply_config_changes(now_timestamp);
+        Self::queue_config_change(&mut perpetual, change, now_timestamp)?;
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn process_cancel_config_change(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        //1.admin signer account
+        let admin_info = next_account_info(account_info_iter)?;
+        //2.perpetual account
+        let perpetual_info = next_account_info(account_info_iter)?;
+        let mut perpetual = Self::unpack_perpetual_as_admin(admin_info, perpetual_info, program_id)?;
+
+        //effective changes can't be cancelled
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        perpetual.apply_config_changes(now_timestamp);
+        if perpetual.secp256k1_pubkey_effective_at == 0
+            && perpetual.gateway_effective_at == 0
+            && perpetual.signers_effective_at == 0
+        {
+            return Err(PerpError::NoPendingConfigChange.into());
+        }
+        perpetual.pending_secp256k1_pubkey = [0; 64];
+        perpetual.secp256k1_pubkey_effective_at = 0;
+        perpetual.pending_gateway = Pubkey::default();
+        perpetual.gateway_effective_at = 0;
+        perpetual.pending_signers.clear();
+        perpetual.pending_signer_threshold = 0;
+        perpetual.signers_effective_at = 0;
+        msg!("Perpetual config change cancelled");
+        Perpetual::store(&perpetual, &mut perpetual_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    fn queue_config_change(
+        perpetual: &mut Perpetual,
+        change: ConfigChange,
+        now_timestamp: i64,
+    ) -> ProgramResult {
+        let effective_at = now_timestamp
+            .checked_add(CONFIG_TIMELOCK)
+            .ok_or(ProgramError::InvalidArgument)?;
+        match change {
+            ConfigChange::Secp256k1Pubkey(secp256k1_pubkey) => {
+                perpetual.pending_secp256k1_pubkey = secp256k1_pubkey;
+                perpetual.secp256k1_pubkey_effective_at = effective_at;
+            }
+            ConfigChange::Gateway(gateway) => {
+                perpetual.pending_gateway = gateway;
+                perpetual.gateway_effective_at = effective_at;
+            }
+            ConfigChange::SignerSet { signers, threshold } => {
+                //empty set with threshold 0 disables multisig
+                let mut sorted = signers.clone();
+                sorted.sort_unstable();
+                sorted.dedup();
+                if signers.len() > MAX_SIGNERS
+                    || sorted.len() != signers.len()
+                    || threshold as usize > signers.len()
+                    || (threshold == 0 && !signers.is_empty())
+                {
+                    msg!("Perpetual invalid signer set:{} threshold:{}", signers.len(), threshold);
+                    return Err(PerpError::InvalidSignerSet.into());
+                }
+                perpetual.pending_signers = signers;
+                perpetual.pending_signer_threshold = threshold;
+                perpetual.signers_effective_at = effective_at;
+            }
+        }
+        msg!("Perpetual config change queued, effective at:{}", effective_at);
+        Ok(())
+    }
+
+    //check signer, owner and admin, return the perpetual to update
+    fn unpack_perpetual_as_admin(
+        admin_info: &AccountInfo,
+        perpetual_info: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Perpetual, ProgramError> {
+        let perpetual = Self::load_perpetual(perpetual_info, program_id)?;
+        let now_timestamp = Clock::get()?.unix_timestamp;
+        Self::check_role(&perpetual, admin_info, &[Role::Admin], now_timestamp)?;
+        Ok(perpetual)
+    }
+
+    //check signer holds one of roles, return the 1st it holds
+    fn check_role(
+        perpetual: &Perpetual,
+        signer_info: &AccountInfo,
+        roles: &[Role],
+        now_timestamp: i64,
+    ) -> Result<Role, ProgramError> {
+        if !signer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        match roles.iter().find(|role| perpetual.has_role(signer_info.key, **role, now_timestamp)) {
+            Some(role) => Ok(*role),
+            None => {
+                msg!("Perpetual signer:{} needs role:{:?}", signer_info.key, roles);
+                let e = match roles {
+                    [Role::Admin] => PerpError::IncorrectAdmin,
+                    [Role::Gateway] => PerpError::IncorrectGateway,
+                    _ => PerpError::Unauthorized,
+                };
+                Err(e.into())
+            }
+        }
+    }
+
+    //check key is the canonical pda of seeds, return the bump to store, only at account creation
+    //when no bump is stored yet: proving a bump canonical tries every higher bump like
+    //find_program_address does, so a bump from the instruction only has to match.
+    //Once stored, check_stored_pda checks the bump with one create_program_address
+    fn check_pda(
+        key: &Pubkey,
+        seeds: &[&[u8]],
+        bump_seed: Option<u8>,
+        program_id: &Pubkey,
+    ) -> Result<u8, ProgramError> {
+        let (pda, canonical) = Pubkey::find_program_address(seeds, program_id);
+        if pda != *key {
+            msg!("Perpetual incorrect pda:{} expected:{}", key, pda);
+            return Err(PerpError::InvalidPda.into());
+        }
+        //other bumps give a 2nd account of the same seeds
+        if bump_seed.is_some_and(|bump_seed| bump_seed != canonical) {
+            msg!("Perpetual non canonical pda bump:{:?}", bump_seed);
+            return Err(PerpError::InvalidPda.into());
+        }
+        Ok(canonical)
+    }
+
+    //check key is the pda of seeds and a stored bump, proven canonical by check_pda
+    //when the account was created, so one create_program_address
+    fn check_stored_pda(
+        key: &Pubkey,
+        seeds: &[&[u8]],
+        bump_seed: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if Self::create_pda(seeds, bump_seed, program_id) != Some(*key) {
+            msg!("Perpetual incorrect pda:{} bump:{}", key, bump_seed);
+            return Err(PerpError::InvalidPda.into());
+        }
+        Ok(())
+    }
+
+    //perpetual account: check owner, discriminator and pda with the stored bump
+    fn load_perpetual(
+        perpetual_info: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Perpetual, ProgramError> {
+        if perpetual_info.owner != program_id {
+            msg!("Perpetual incorrect perpetual account:{}", perpetual_info.owner);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let perpetual = Perpetual::load(&perpetual_info.data.borrow())?;
+        Self::check_stored_pda(perpetual_info.key, &[PERPETUAL_SEED], perpetual.bump_seed, program_id)?;
+        Ok(perpetual)
+    }
+
+    //user account: check owner, discriminator and pda of the stored user and bump
+    fn load_user_account(
+        account_info: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Account, ProgramError> {
+        if account_info.owner != program_id {
+            msg!("Perpetual incorrect user account:{}", account_info.owner);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let account = Account::load(&account_info.data.borrow())?;
+        Self::check_stored_pda(account_info.key, &[PERPETUAL_SEED, account.user.as_ref()], account.bump_seed, program_id)?;
+        Ok(account)
+    }
+
+    //token config of account_type and symbol: check owner, discriminator,
+    //stored token and pda with the stored bump
+    fn load_token_config(
+        token_config_info: &AccountInfo,
+        account_type: u8,
+        symbol: &str,
+        program_id: &Pubkey,
+    ) -> Result<TokenConfig, ProgramError> {
+        //never added or removed
+        if token_config_info.data_is_empty() {
+            msg!("Perpetual unsupported token type:{} symbol:{}", account_type, symbol);
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if token_config_info.owner != program_id {
+            msg!("Perpetual incorrect token config account:{}", token_config_info.owner);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let token_config = TokenConfig::load(&token_config_info.data.borrow())?;
+        if token_config.account_type != account_type || token_config.symbol != symbol {
+            msg!("Perpetual incorrect token config type:{} symbol:{}", token_config.account_type, token_config.symbol);
+            return Err(ProgramError::InvalidArgument);
+        }
+        let token_seeds: &[&[u8]] = &[TOKEN_SEED, &[account_type], symbol.as_bytes()];
+        Self::check_stored_pda(token_config_info.key, token_seeds, token_config.bump_seed, program_id)?;
+        Ok(token_config)
+    }
+
+    fn create_pda(seeds: &[&[u8]], bump_seed: u8, program_id: &Pubkey) -> Option<Pubkey> {
+        let bump = [bump_seed];
+        let mut seeds_with_bump = seeds.to_vec();
+        seeds_with_bump.push(&bump);
+        Pubkey::create_program_address(&seeds_with_bump, program_id).ok()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{find_account_address, find_perpetual_address, find_token_address, instruction, instruction::{BatchWithdrawAccounts, MAX_SYMBOL_LEN}};
+    use crate::state::{WithdrawWindow, DEFAULT_MAX_WITHDRAW_VALIDITY, LEGACY_ACCOUNT_BYTES, LEGACY_PERPETUAL_BYTES, MAX_ACCOUNT_TYPES, WITHDRAW_WINDOW};
+    use crate::test_utils::Rng;
+    use arrayref::array_ref;
+    use borsh::BorshSerialize;
+    use std::collections::BTreeMap;
+    use solana_program::{
+        entrypoint::MAX_PERMITTED_DATA_INCREASE,
+        instruction::{AccountMeta, Instruction},
+        program_utils::limited_deserialize,
+        system_instruction::SystemInstruction,
+    };
+
+    //lamports, data and owner of one account of an instruction, key and flags are its AccountMeta
+    struct TestAccount {
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+    }
+
+    impl TestAccount {
+        fn new(data: Vec<u8>, owner: Pubkey) -> Self {
+            TestAccount { lamports: 1_000_000_000, data, owner }
+        }
+
+        fn system() -> Self {
+            Self::new(Vec::new(), solana_program::system_program::id())
+        }
+    }
+
+    //key as serialized by the runtime, realloc reads the original data length before it
+    #[repr(C)]
+    struct SerializedKey {
+        original_data_len: u32,
+        key: Pubkey,
+    }
+
+    //data as serialized by the runtime: u64 length, data, room to grow, 8 byte aligned
+    fn serialized_data(data: &[u8]) -> Vec<u64> {
+        let mut buf = vec![0u64; 1 + (data.len() + MAX_PERMITTED_DATA_INCREASE).div_ceil(8)];
+        let bytes = as_bytes(&mut buf);
+        bytes[..8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        bytes[8..8 + data.len()].copy_from_slice(data);
+        buf
+    }
+
+    fn as_bytes(buf: &mut [u64]) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len() * 8) }
+    }
+
+    fn process(ix: &Instruction, accounts: &mut [TestAccount]) -> ProgramResult {
+        assert_eq!(ix.accounts.len(), accounts.len());
+        let keys: Vec<SerializedKey> = ix
+            .accounts
+            .iter()
+            .zip(accounts.iter())
+            .map(|(meta, account)| SerializedKey { original_data_len: account.data.len() as u32, key: meta.pubkey })
+            .collect();
+        //a key listed twice is one account, as in the runtime, the 1st occurrence
+        let first: Vec<usize> = ix
+            .accounts
+            .iter()
+            .map(|meta| ix.accounts.iter().position(|other| other.pubkey == meta.pubkey).unwrap())
+            .collect();
+        let mut bufs: Vec<Vec<u64>> = accounts.iter().map(|account| serialized_data(&account.data)).collect();
+        let result = {
+            let mut infos: Vec<AccountInfo> = Vec::new();
+            for (i, (((meta, key), account), buf)) in
+                ix.accounts.iter().zip(&keys).zip(accounts.iter_mut()).zip(bufs.iter_mut()).enumerate()
+            {
+                if first[i] != i {
+                    let info = infos[first[i]].clone();
+                    infos.push(info);
+                    continue;
+                }
+                let len = account.data.len();
+                infos.push(AccountInfo::new(
+                    &key.key,
+                    meta.is_signer,
+                    meta.is_writable,
+                    &mut account.lamports,
+                    &mut as_bytes(buf)[8..8 + len],
+                    &account.owner,
+                    false,
+                    0,
+                ));
+            }
+            Processor::process(&ix.program_id, &infos, &ix.data)
+        };
+        //data back at its length after a realloc
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            let bytes = as_bytes(buf);
+            let len = u64::from_le_bytes(*array_ref![bytes, 0, 8]) as usize;
+            accounts[i].data = bytes[8..8 + len].to_vec();
+        }
+        for (i, first) in first.into_iter().enumerate() {
+            if first != i {
+                accounts[i].data = accounts[first].data.clone();
+            }
+        }
+        result
+    }
+
+    //a valid bump below the canonical one, the pda of a 2nd account
+    fn non_canonical(seeds: &[&[u8]], program_id: &Pubkey) -> (Pubkey, u8) {
+        let (_, canonical) = Pubkey::find_program_address(seeds, program_id);
+        (0..canonical)
+            .rev()
+            .find_map(|bump| Processor::create_pda(seeds, bump, program_id).map(|key| (key, bump)))
+            .unwrap()
+    }
+
+    fn invalid_pda() -> ProgramError {
+        PerpError::InvalidPda.into()
+    }
+
+    //unix timestamp of the Clock stub
+    const NOW: i64 = 1_700_000_000;
+
+    //Clock::get and Rent::get off chain, same time for every test,
+    //system program create_account and transfer cpis
+    struct ProgramStubs;
+
+    impl solana_program::program_stubs::SyscallStubs for ProgramStubs {
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock { unix_timestamp: NOW, ..Clock::default() };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            solana_program::entrypoint::SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            solana_program::entrypoint::SUCCESS
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            if instruction.program_id != solana_program::system_program::id() {
+                return Ok(());
+            }
+            let info = |i: usize| {
+                account_infos.iter().find(|info| *info.key == instruction.accounts[i].pubkey).unwrap()
+            };
+            let (from, to) = (info(0), info(1));
+            let lamports = match limited_deserialize(&instruction.data, 1024).unwrap() {
+                SystemInstruction::CreateAccount { lamports, space, owner } => {
+                    to.realloc(space as usize, true)?;
+                    to.assign(&owner);
+                    lamports
+                }
+                SystemInstruction::Transfer { lamports } => lamports,
+                _ => return Ok(()),
+            };
+            **from.lamports.borrow_mut() -= lamports;
+            **to.lamports.borrow_mut() += lamports;
+            Ok(())
+        }
+    }
+
+    //admin, gateway, guardian and the perpetual account holding them
+    struct Roles {
+        program_id: Pubkey,
+        admin: Pubkey,
+        gateway: Pubkey,
+        guardian: Pubkey,
+    }
+
+    impl Roles {
+        fn new() -> Self {
+            static STUBS: std::sync::Once = std::sync::Once::new();
+            STUBS.call_once(|| {
+                solana_program::program_stubs::set_syscall_stubs(Box::new(ProgramStubs));
+            });
+            Roles {
+                program_id: Pubkey::new_unique(),
+                admin: Pubkey::new_unique(),
+                gateway: Pubkey::new_unique(),
+                guardian: Pubkey::new_unique(),
+            }
+        }
+
+        fn perpetual(&self) -> TestAccount {
+            let (_, bump) = find_perpetual_address(&self.program_id);
+            let mut perpetual = Perpetual::new([1; 64], self.gateway, self.admin, bump);
+            perpetual.guardians = vec![self.guardian];
+            let mut data = vec![0; PERPETUAL_BYTES];
+            perpetual.store(&mut data).unwrap();
+            TestAccount::new(data, self.program_id)
+        }
+
+        //with the window of account type 1, as after its first withdraw
+        fn user_account(&self, user: &Pubkey) -> TestAccount {
+            let mut data = vec![0; ACCOUNT_BYTES];
+            let (_, bump_seed) = find_account_address(&self.program_id, user);
+            let mut account = Account::new(*user, bump_seed);
+            account.withdraw_id.insert(1, WithdrawWindow::new());
+            account.store(&mut data).unwrap();
+            TestAccount::new(data, self.program_id)
+        }
+
+        fn token_config(&self, account_type: u8, symbol: &str) -> TestAccount {
+            let (_, bump_seed) = find_token_address(&self.program_id, account_type, symbol);
+            let mut data = vec![0; TOKEN_CONFIG_BYTES];
+            TokenConfig::new(account_type, symbol.to_string(), bump_seed).unwrap().store(&mut data).unwrap();
+            TestAccount::new(data, self.program_id)
+        }
+    }
+
+    //builds the instruction of a role gated path for a signer
+    type Build<'a> = &'a dyn Fn(&Pubkey) -> Instruction;
+
+    #[test]
+    fn admin_only_paths() {
+        let roles = Roles::new();
+        let outsider = Pubkey::new_unique();
+        let new_gateway = ConfigChange::Gateway(Pubkey::new_unique());
+        //builder and the result for the admin
+        let builders: [(Build, ProgramResult); 7] = [
+            (&|signer| instruction::set_max_withdraw_validity(&roles.program_id, signer, 60).unwrap(), Ok(())),
+            (&|signer| instruction::set_guardians(&roles.program_id, signer, vec![Pubkey::new_unique()]).unwrap(), Ok(())),
+            (&|signer| instruction::set_signature_scheme(&roles.program_id, signer, SignatureScheme::Keccak).unwrap(), Ok(())),
+            (&|signer| instruction::propose_admin(&roles.program_id, signer, &Pubkey::new_unique()).unwrap(), Ok(())),
+            (&|signer| instruction::cancel_admin_proposal(&roles.program_id, signer).unwrap(), Ok(())),
+            (&|signer| instruction::queue_config_change(&roles.program_id, signer, new_gateway.clone()).unwrap(), Ok(())),
+            (
+                &|signer| instruction::cancel_config_change(&roles.program_id, signer).unwrap(),
+                Err(PerpError::NoPendingConfigChange.into()),
+            ),
+        ];
+        for (build, admin_result) in builders {
+            for signer in [roles.gateway, roles.guardian, outsider] {
+                let ix = build(&signer);
+                assert_eq!(process(&ix, &mut [TestAccount::system(), roles.perpetual()]), Err(PerpError::IncorrectAdmin.into()));
+            }
+            let mut ix = build(&roles.admin);
+            ix.accounts[0].is_signer = false;
+            assert_eq!(
+                process(&ix, &mut [TestAccount::system(), roles.perpetual()]),
+                Err(ProgramError::MissingRequiredSignature)
+            );
+            ix.accounts[0].is_signer = true;
+            assert_eq!(process(&ix, &mut [TestAccount::system(), roles.perpetual()]), admin_result);
+        }
+    }
+
+    #[test]
+    fn admin_handover() {
+        let roles = Roles::new();
+        let (perpetual_key, _) = find_perpetual_address(&roles.program_id);
+        let new_admin = Pubkey::new_unique();
+        let mut perpetual = roles.perpetual().data;
+        //the perpetual account is kept when the instruction succeeds
+        let mut run = |ix: Instruction| {
+            let mut accounts: Vec<TestAccount> = ix
+                .accounts
+                .iter()
+                .map(|meta| if meta.pubkey == perpetual_key {
+                    TestAccount::new(perpetual.clone(), roles.program_id)
+                } else {
+                    TestAccount::system()
+                })
+                .collect();
+            process(&ix, &mut accounts)?;
+            perpetual = accounts[1].data.clone();
+            Ok(Perpetual::load(&perpetual).unwrap())
+        };
+        let propose = |signer: &Pubkey| instruction::propose_admin(&roles.program_id, signer, &new_admin).unwrap();
+        let accept = |signer: &Pubkey| instruction::accept_admin(&roles.program_id, signer).unwrap();
+
+        //nothing proposed
+        assert_eq!(run(accept(&new_admin)).err(), Some(PerpError::NoPendingAdmin.into()));
+        //proposed, only the proposed key accepts
+        assert_eq!(run(propose(&new_admin)).err(), Some(PerpError::IncorrectAdmin.into()));
+        assert_eq!(run(propose(&roles.admin)).unwrap().pending_admin, new_admin);
+        for signer in [roles.admin, roles.gateway, Pubkey::new_unique()] {
+            assert_eq!(run(accept(&signer)).err(), Some(PerpError::IncorrectAdmin.into()));
+        }
+        //cancelled
+        let cancelled = run(instruction::cancel_admin_proposal(&roles.program_id, &roles.admin).unwrap()).unwrap();
+        assert_eq!(cancelled.pending_admin, Pubkey::default());
+        assert_eq!(run(accept(&new_admin)).err(), Some(PerpError::NoPendingAdmin.into()));
+
+        //accepted, the old admin is out
+        run(propose(&roles.admin)).unwrap();
+        let accepted = run(accept(&new_admin)).unwrap();
+        assert_eq!((accepted.admin, accepted.pending_admin), (new_admin, Pubkey::default()));
+        assert_eq!(run(accept(&new_admin)).err(), Some(PerpError::NoPendingAdmin.into()));
+        assert_eq!(run(propose(&roles.admin)).err(), Some(PerpError::IncorrectAdmin.into()));
+
+        //InitPerpetual never changes the admin
+        let init = |signer: &Pubkey, admin: &Pubkey| instruction::init_perpetual(&roles.program_id, signer, [1; 64], &roles.gateway, admin).unwrap();
+        assert_eq!(run(init(&new_admin, &roles.admin)).err(), Some(PerpError::AdminTransferRequired.into()));
+        assert_eq!(run(init(&new_admin, &Pubkey::new_unique())).err(), Some(PerpError::AdminTransferRequired.into()));
+        assert_eq!(run(init(&roles.admin, &roles.admin)).err(), Some(ProgramError::InvalidAccountData));
+        assert_eq!(run(init(&new_admin, &new_admin)).unwrap().admin, new_admin);
+    }
+
+    #[test]
+    fn token_admin_only_paths() {
+        let roles = Roles::new();
+        let outsider = Pubkey::new_unique();
+        let builders: [Build; 3] = [
+            &|signer| instruction::set_multisig_amount(&roles.program_id, signer, 1, "USDC", 100).unwrap(),
+            &|signer| instruction::set_rate_limit(&roles.program_id, signer, 1, "USDC", 60, 100).unwrap(),
+            &|signer| instruction::set_withdraw_limits(&roles.program_id, signer, 1, "USDC", 1, 2, 3).unwrap(),
+        ];
+        let accounts = || [TestAccount::system(), roles.perpetual(), roles.token_config(1, "USDC")];
+        for build in builders {
+            for signer in [roles.gateway, roles.guardian, outsider] {
+                assert_eq!(process(&build(&signer), &mut accounts()), Err(PerpError::IncorrectAdmin.into()));
+            }
+            assert_eq!(process(&build(&roles.admin), &mut accounts()), Ok(()));
+        }
+
+        //SetTokenMap keeps the error of the legacy program, the admin removes the token
+        let (program_token, mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        for signer in [roles.gateway, roles.guardian, outsider, roles.admin] {
+            let ix = instruction::set_token_map(&roles.program_id, &signer, &program_token, &mint, 1, "USDC").unwrap();
+            let mut accounts: Vec<TestAccount> = ix.accounts.iter().map(|_| TestAccount::system()).collect();
+            accounts[1] = roles.perpetual();
+            accounts[3] = roles.token_config(1, "USDC");
+            let expected = if signer == roles.admin { Ok(()) } else { Err(ProgramError::InvalidAccountData) };
+            assert_eq!(process(&ix, &mut accounts), expected);
+        }
+    }
+
+    #[test]
+    fn token_pause_engage_and_clear() {
+        let roles = Roles::new();
+        let mut accounts = [TestAccount::system(), roles.perpetual(), roles.token_config(1, "USDC")];
+        let mut pause = |signer: &Pubkey, flags: u8| {
+            let token = Some(TypeSymbol { account_type: 1, symbol: "USDC".to_string() });
+            let ix = instruction::set_pause(&roles.program_id, signer, token, flags).unwrap();
+            process(&ix, &mut accounts)?;
+            TokenConfig::load(&accounts[2].data).map(|token_config| token_config.pause_flags)
+        };
+
+        assert_eq!(pause(&Pubkey::new_unique(), PAUSE_WITHDRAW), Err(PerpError::Unauthorized.into()));
+        assert_eq!(pause(&roles.guardian, PAUSE_WITHDRAW), Ok(PAUSE_WITHDRAW));
+        assert_eq!(pause(&roles.gateway, PAUSE_ALL), Ok(PAUSE_ALL));
+        for signer in [roles.guardian, roles.gateway, Pubkey::new_unique()] {
+            assert_eq!(pause(&signer, PAUSE_DEPOSIT), Err(PerpError::IncorrectAdmin.into()));
+        }
+        assert_eq!(pause(&roles.admin, 0), Ok(0));
+    }
+
+    #[test]
+    fn gateway_rotates_after_timelock() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let new_gateway = Pubkey::new_unique();
+        let mut accounts = [TestAccount::system(), roles.perpetual()];
+        let ix = instruction::queue_config_change(&roles.program_id, &roles.admin, ConfigChange::Gateway(new_gateway)).unwrap();
+        assert_eq!(process(&ix, &mut accounts), Ok(()));
+        let mut perpetual_data = accounts[1].data.clone();
+        let cancel = |signer: &Pubkey, withdrawid: u64, perpetual_data: &[u8]| {
+            let ix = instruction::cancel_withdraw_id(&roles.program_id, signer, &user, 1, withdrawid, None).unwrap();
+            let perpetual = TestAccount::new(perpetual_data.to_vec(), roles.program_id);
+            process(&ix, &mut [TestAccount::system(), roles.user_account(&user), perpetual])
+        };
+
+        //queued, the old gateway keeps the role until the timelock elapses
+        let mut perpetual = Perpetual::load(&perpetual_data).unwrap();
+        let now = perpetual.gateway_effective_at - CONFIG_TIMELOCK;
+        assert_eq!(perpetual.gateway, roles.gateway);
+        assert_eq!(cancel(&new_gateway, 1, &perpetual_data), Err(PerpError::Unauthorized.into()));
+        assert_eq!(cancel(&roles.gateway, 1, &perpetual_data), Ok(()));
+
+        //one second before
+        perpetual.gateway_effective_at = now + 1;
+        perpetual.store(&mut perpetual_data).unwrap();
+        assert_eq!(cancel(&new_gateway, 1, &perpetual_data), Err(PerpError::Unauthorized.into()));
+
+        //elapsed, the new gateway takes over
+        perpetual.gateway_effective_at = now;
+        perpetual.store(&mut perpetual_data).unwrap();
+        assert_eq!(cancel(&new_gateway, 1, &perpetual_data), Ok(()));
+        assert_eq!(cancel(&roles.gateway, 1, &perpetual_data), Err(PerpError::Unauthorized.into()));
+    }
+
+    #[test]
+    fn gateway_only_paths() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let entry = BatchWithdrawEntry {
+            account_type: 1,
+            symbol: "USDC".to_string(),
+            amount: 1,
+            withdrawid: 0,
+            timestamp: 0,
+            signatures: vec![RecoverableSignature { recovery_id: 0, signature: [1; 64] }],
+        };
+        let entry_accounts = BatchWithdrawAccounts {
+            user,
+            user_token_account: Pubkey::new_unique(),
+            program_token_account: Pubkey::new_unique(),
+        };
+        for signer in [roles.admin, roles.guardian, Pubkey::new_unique()] {
+            let ix = instruction::batch_withdraw(&roles.program_id, &signer, vec![entry.clone()], &[entry_accounts]).unwrap();
+            let mut accounts: Vec<TestAccount> = ix.accounts.iter().map(|_| TestAccount::system()).collect();
+            accounts[1] = roles.perpetual();
+            assert_eq!(process(&ix, &mut accounts), Err(PerpError::IncorrectGateway.into()));
+
+            let ix = instruction::relayed_withdraw(
+                &roles.program_id,
+                &signer,
+                &user,
+                &entry_accounts.user_token_account,
+                &entry_accounts.program_token_account,
+                1,
+                "USDC",
+                1,
+                0,
+                0,
+                entry.signatures.clone(),
+            )
+            .unwrap();
+            let mut accounts: Vec<TestAccount> = ix.accounts.iter().map(|_| TestAccount::system()).collect();
+            accounts[4] = roles.perpetual();
+            assert_eq!(process(&ix, &mut accounts), Err(PerpError::IncorrectGateway.into()));
+        }
+
+        //the gateway gets past the role check, fails on the empty entry accounts
+        let ix = instruction::batch_withdraw(&roles.program_id, &roles.gateway, vec![entry], &[entry_accounts]).unwrap();
+        let mut accounts: Vec<TestAccount> = ix.accounts.iter().map(|_| TestAccount::system()).collect();
+        accounts[1] = roles.perpetual();
+        let result = process(&ix, &mut accounts);
+        assert!(result.is_err());
+        assert_ne!(result, Err(PerpError::IncorrectGateway.into()));
+    }
+
+    #[test]
+    fn cancel_withdraw_id_admin_or_gateway() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let mut accounts = [TestAccount::system(), roles.user_account(&user), roles.perpetual()];
+        let mut cancel = |signer: &Pubkey, withdrawid: u64| {
+            let ix = instruction::cancel_withdraw_id(&roles.program_id, signer, &user, 1, withdrawid, None).unwrap();
+            process(&ix, &mut accounts)
+        };
+
+        //ids start from 1
+        assert_eq!(cancel(&roles.admin, 1), Ok(()));
+        assert_eq!(cancel(&roles.gateway, 2), Ok(()));
+        for signer in [roles.guardian, user, Pubkey::new_unique()] {
+            assert_eq!(cancel(&signer, 3), Err(PerpError::Unauthorized.into()));
+        }
+        //the gateway only burns ids inside the window, never the ones after it
+        assert_eq!(cancel(&roles.gateway, 1 + WITHDRAW_WINDOW), Err(PerpError::WithdrawIdFail.into()));
+        assert_eq!(cancel(&roles.gateway, 1), Err(PerpError::WithdrawIdFail.into()));
+        //cancels don't move the window, a run of them ends at its top
+        for withdrawid in 3..=WITHDRAW_WINDOW {
+            assert_eq!(cancel(&roles.gateway, withdrawid), Ok(()));
+        }
+        assert_eq!(cancel(&roles.gateway, 1 + WITHDRAW_WINDOW), Err(PerpError::WithdrawIdFail.into()));
+        let account = Account::load(&accounts[1].data).unwrap();
+        assert_eq!(account.withdraw_id[&1].base, 1);
+    }
+
+    #[test]
+    fn cancel_never_grows_the_withdraw_id_map() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let mut accounts = [TestAccount::system(), roles.user_account(&user), roles.perpetual()];
+        //made up account types without a withdraw get no window
+        for account_type in 2..=(MAX_ACCOUNT_TYPES as u8 + 1) {
+            let ix = instruction::cancel_withdraw_id(&roles.program_id, &roles.gateway, &user, account_type, 1, None).unwrap();
+            assert_eq!(process(&ix, &mut accounts), Err(PerpError::WithdrawIdFail.into()));
+        }
+        let mut account = Account::load(&accounts[1].data).unwrap();
+        assert_eq!(account.withdraw_id.keys().copied().collect::<Vec<_>>(), vec![1]);
+        //every unused account type still gets a window on its first withdraw
+        for account_type in 2..=MAX_ACCOUNT_TYPES as u8 {
+            assert_eq!(account.use_withdraw_id(account_type, 1), Ok(()));
+        }
+    }
+
+    #[test]
+    fn pause_engage_and_clear() {
+        let roles = Roles::new();
+        let mut accounts = [TestAccount::system(), roles.perpetual()];
+        let mut pause = |signer: &Pubkey, flags: u8| {
+            let ix = instruction::set_pause(&roles.program_id, signer, None, flags).unwrap();
+            process(&ix, &mut accounts)?;
+            Perpetual::load(&accounts[1].data).map(|perpetual| perpetual.pause_flags)
+        };
+
+        //anyone with a role engages
+        assert_eq!(pause(&Pubkey::new_unique(), PAUSE_WITHDRAW), Err(PerpError::Unauthorized.into()));
+        assert_eq!(pause(&roles.guardian, PAUSE_WITHDRAW), Ok(PAUSE_WITHDRAW));
+        assert_eq!(pause(&roles.gateway, PAUSE_ALL), Ok(PAUSE_ALL));
+        assert_eq!(pause(&roles.guardian, PAUSE_ALL), Ok(PAUSE_ALL));
+        //only the admin clears, any flag
+        for signer in [roles.guardian, roles.gateway] {
+            assert_eq!(pause(&signer, PAUSE_WITHDRAW), Err(PerpError::IncorrectAdmin.into()));
+            assert_eq!(pause(&signer, 0), Err(PerpError::IncorrectAdmin.into()));
+        }
+        assert_eq!(pause(&roles.admin, PAUSE_DEPOSIT), Ok(PAUSE_DEPOSIT));
+        assert_eq!(pause(&roles.admin, 0), Ok(0));
+    }
+
+    #[test]
+    fn check_pda_canonical_only() {
+        let program_id = Pubkey::new_unique();
+        let seeds: &[&[u8]] = &[PERPETUAL_SEED];
+        let (pda, bump) = find_perpetual_address(&program_id);
+        assert_eq!(Processor::check_pda(&pda, seeds, None, &program_id), Ok(bump));
+        assert_eq!(Processor::check_pda(&pda, seeds, Some(bump), &program_id), Ok(bump));
+        //wrong address
+        let other = Pubkey::new_unique();
+        assert_eq!(Processor::check_pda(&other, seeds, None, &program_id), Err(invalid_pda()));
+        assert_eq!(Processor::check_pda(&other, seeds, Some(bump), &program_id), Err(invalid_pda()));
+        //a valid pda of a lower bump
+        let (lower, lower_bump) = non_canonical(seeds, &program_id);
+        assert_eq!(Processor::check_pda(&lower, seeds, Some(lower_bump), &program_id), Err(invalid_pda()));
+        assert_eq!(Processor::check_pda(&lower, seeds, None, &program_id), Err(invalid_pda()));
+        assert_eq!(Processor::check_pda(&pda, seeds, Some(lower_bump), &program_id), Err(invalid_pda()));
+    }
+
+    #[test]
+    fn check_stored_pda_one_bump() {
+        let program_id = Pubkey::new_unique();
+        let seeds: &[&[u8]] = &[PERPETUAL_SEED];
+        let (pda, bump) = find_perpetual_address(&program_id);
+        assert_eq!(Processor::check_stored_pda(&pda, seeds, bump, &program_id), Ok(()));
+        assert_eq!(Processor::check_stored_pda(&pda, seeds, bump.wrapping_sub(1), &program_id), Err(invalid_pda()));
+        assert_eq!(Processor::check_stored_pda(&Pubkey::new_unique(), seeds, bump, &program_id), Err(invalid_pda()));
+    }
+
+    #[test]
+    fn init_perpetual_rejects_wrong_pda() {
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let mut ix = instruction::init_perpetual(&program_id, &admin, [1; 64], &Pubkey::new_unique(), &admin).unwrap();
+        let accounts = || vec![TestAccount::system(), TestAccount::system(), TestAccount::system()];
+
+        //wrong address, bump from the builder
+        ix.accounts[1].pubkey = Pubkey::new_unique();
+        assert_eq!(process(&ix, &mut accounts()), Err(invalid_pda()));
+
+        //2nd pda of a non canonical bump
+        let (lower, lower_bump) = non_canonical(&[PERPETUAL_SEED], &program_id);
+        ix.accounts[1].pubkey = lower;
+        ix.data = PerpetualInstruction::InitPerpetual {
+            secp256k1_pubkey: [1; 64],
+            gateway: Pubkey::new_unique(),
+            admin,
+            bump_seed: Some(lower_bump),
+        }
+        .pack()
+        .unwrap();
+        assert_eq!(process(&ix, &mut accounts()), Err(invalid_pda()));
+    }
+
+    #[test]
+    fn init_perpetual_update_checks_stored_bump() {
+        let roles = Roles::new();
+        let (_, bump) = find_perpetual_address(&roles.program_id);
+        let init = |bump_seed: Option<u8>, perpetual: TestAccount| {
+            let mut ix = instruction::init_perpetual(&roles.program_id, &roles.admin, [1; 64], &roles.gateway, &roles.admin).unwrap();
+            ix.data = PerpetualInstruction::InitPerpetual { secp256k1_pubkey: [1; 64], gateway: roles.gateway, admin: roles.admin, bump_seed }
+                .pack()
+                .unwrap();
+            process(&ix, &mut [TestAccount::system(), perpetual, TestAccount::system()])
+        };
+
+        //the stored bump, or none
+        assert_eq!(init(Some(bump), roles.perpetual()), Ok(()));
+        assert_eq!(init(None, roles.perpetual()), Ok(()));
+        //any other bump
+        assert_eq!(init(Some(bump.wrapping_sub(1)), roles.perpetual()), Err(invalid_pda()));
+        //a wrong stored bump is not the account key
+        let mut perpetual = roles.perpetual();
+        let mut stored = Perpetual::load(&perpetual.data).unwrap();
+        stored.bump_seed = bump.wrapping_sub(1);
+        stored.store(&mut perpetual.data).unwrap();
+        assert_eq!(init(stored.bump_seed.into(), perpetual), Err(invalid_pda()));
+    }
+
+    #[test]
+    fn init_account_rejects_wrong_pda() {
+        let program_id = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let mut ix = instruction::init_account(&program_id, &user).unwrap();
+        let accounts = || vec![TestAccount::system(), TestAccount::system(), TestAccount::system()];
+
+        //another user's account
+        let (other, _) = find_account_address(&program_id, &Pubkey::new_unique());
+        ix.accounts[1].pubkey = other;
+        assert_eq!(process(&ix, &mut accounts()), Err(invalid_pda()));
+
+        let (lower, lower_bump) = non_canonical(&[PERPETUAL_SEED, user.as_ref()], &program_id);
+        ix.accounts[1].pubkey = lower;
+        ix.data = PerpetualInstruction::InitAccount { bump_seed: Some(lower_bump) }.pack().unwrap();
+        assert_eq!(process(&ix, &mut accounts()), Err(invalid_pda()));
+    }
+
+    #[test]
+    fn load_user_account_stored_bump() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let (key, bump) = find_account_address(&roles.program_id, &user);
+        let load = |bump_seed: u8| {
+            let mut account = roles.user_account(&user);
+            Account::new(user, bump_seed).store(&mut account.data).unwrap();
+            let info = AccountInfo::new(&key, false, true, &mut account.lamports, &mut account.data, &account.owner, false, 0);
+            Processor::load_user_account(&info, &roles.program_id).map(|account| account.bump_seed)
+        };
+        assert_eq!(load(bump), Ok(bump));
+        //a wrong stored bump is not the account key
+        assert_eq!(load(bump.wrapping_sub(1)), Err(invalid_pda()));
+    }
+
+    //signatures of `count` distinct keys over hash, sorted by the recovered key,
+    //random r and a low s recover to some key, no signing needed
+    fn multisig_signers(hash: &[u8; 32], count: usize) -> Vec<([u8; 64], RecoverableSignature)> {
+        let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+        let mut signers = Vec::new();
+        while signers.len() < count {
+            let mut signature = [0u8; 64];
+            signature.copy_from_slice(&rng.bytes(64));
+            signature[32] &= 0x3f;
+            if let Ok(key) = recover_signer(hash, 0, &signature) {
+                signers.push((key, RecoverableSignature { recovery_id: 0, signature }));
+            }
+        }
+        signers.sort_by_key(|(key, _)| *key);
+        signers
+    }
+
+    #[test]
+    fn multisig_threshold_and_order() {
+        let hash = [0x11; 32];
+        let (keys, sigs): (Vec<[u8; 64]>, Vec<RecoverableSignature>) = multisig_signers(&hash, 4).into_iter().unzip();
+        //2 of the first 3 keys, the 4th is outside the set
+        let mut perpetual = Perpetual::new([1; 64], Pubkey::new_unique(), Pubkey::new_unique(), 255);
+        perpetual.signers = keys[..3].to_vec();
+        perpetual.signer_threshold = 2;
+        let check = |perpetual: &Perpetual, signatures: &[RecoverableSignature]| {
+            Processor::check_multisig(perpetual, &hash, signatures, 1_700_000_000)
+        };
+
+        //threshold met
+        assert_eq!(check(&perpetual, &sigs[..2]), Ok(()));
+        assert_eq!(check(&perpetual, &[sigs[0], sigs[2]]), Ok(()));
+        assert_eq!(check(&perpetual, &sigs[..3]), Ok(()));
+        //threshold not met, or more signatures than keys
+        assert_eq!(check(&perpetual, &sigs[..1]), Err(PerpError::ThresholdNotMet.into()));
+        assert_eq!(check(&perpetual, &[]), Err(PerpError::ThresholdNotMet.into()));
+        assert_eq!(check(&perpetual, &sigs), Err(PerpError::ThresholdNotMet.into()));
+        //a key counts once, in ascending order
+        assert_eq!(check(&perpetual, &[sigs[0], sigs[0]]), Err(PerpError::SignersNotAscending.into()));
+        assert_eq!(check(&perpetual, &[sigs[1], sigs[0]]), Err(PerpError::SignersNotAscending.into()));
+        assert_eq!(check(&perpetual, &[sigs[0], sigs[2], sigs[1]]), Err(PerpError::SignersNotAscending.into()));
+        //a key outside the set
+        assert_eq!(check(&perpetual, &[sigs[0], sigs[3]]), Err(PerpError::SignerNotInSet.into()));
+        //a signature over another hash recovers another key
+        let other = Processor::check_multisig(&perpetual, &[0x22; 32], &sigs[..2], 1_700_000_000);
+        assert!(other.is_err());
+
+        //disabled, no set
+        perpetual.signers.clear();
+        perpetual.signer_threshold = 0;
+        assert_eq!(check(&perpetual, &sigs[..2]), Err(PerpError::ThresholdNotMet.into()));
+    }
+
+    #[test]
+    fn queue_invalid_signer_set() {
+        let roles = Roles::new();
+        let queue = |signers: Vec<[u8; 64]>, threshold: u8| {
+            let change = ConfigChange::SignerSet { signers, threshold };
+            let ix = instruction::queue_config_change(&roles.program_id, &roles.admin, change).unwrap();
+            process(&ix, &mut [TestAccount::system(), roles.perpetual()])
+        };
+        let keys: Vec<[u8; 64]> = (1..=MAX_SIGNERS as u8 + 1).map(|i| [i; 64]).collect();
+
+        assert_eq!(queue(keys[..MAX_SIGNERS].to_vec(), MAX_SIGNERS as u8), Ok(()));
+        assert_eq!(queue(keys[..2].to_vec(), 1), Ok(()));
+        //empty with threshold 0 disables multisig
+        assert_eq!(queue(vec![], 0), Ok(()));
+        //too many keys, a duplicate, threshold above the keys, threshold 0 of keys
+        assert_eq!(queue(keys.clone(), 1), Err(PerpError::InvalidSignerSet.into()));
+        assert_eq!(queue(vec![keys[0], keys[1], keys[0]], 2), Err(PerpError::InvalidSignerSet.into()));
+        assert_eq!(queue(keys[..2].to_vec(), 3), Err(PerpError::InvalidSignerSet.into()));
+        assert_eq!(queue(vec![], 1), Err(PerpError::InvalidSignerSet.into()));
+        assert_eq!(queue(keys[..2].to_vec(), 0), Err(PerpError::InvalidSignerSet.into()));
+    }
+
+    fn mint_account() -> TestAccount {
+        let mut data = vec![0; Mint::LEN];
+        Mint::pack(Mint { decimals: 6, is_initialized: true, ..Mint::default() }, &mut data).unwrap();
+        TestAccount::new(data, spl_token::id())
+    }
+
+    #[test]
+    fn migrate_perpetual_in_steps() {
+        let roles = Roles::new();
+        let (_, bump_seed) = find_perpetual_address(&roles.program_id);
+        let (btc_mint, eth_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let token = |account_type: u8, symbol: &str| TypeSymbol { account_type, symbol: symbol.to_string() };
+        let mint_program = |mint: Pubkey| LegacyMintProgram { mint, program_token_account: Pubkey::new_unique() };
+        //in map order, an empty and a too long symbol first, one past the 32 byte pda seed last
+        let token_map = [
+            (token(1, ""), mint_program(Pubkey::new_unique())),
+            (token(1, &"A".repeat(MAX_SYMBOL_LEN + 1)), mint_program(Pubkey::new_unique())),
+            (token(1, "BTC"), mint_program(btc_mint)),
+            (token(1, "ETH"), mint_program(eth_mint)),
+            (token(2, &"S".repeat(40)), mint_program(Pubkey::new_unique())),
+        ]
+        .into_iter()
+        .collect();
+        let legacy = LegacyPerpetual {
+            is_initialized: true,
+            secp256k1_pubkey: [1; 64],
+            gateway: roles.gateway,
+            admin: roles.admin,
+            bump_seed,
+            token_map,
+        };
+        let mut data = vec![0; LEGACY_PERPETUAL_BYTES];
+        legacy.pack(&mut data).unwrap();
+        let mut perpetual = TestAccount::new(data, roles.program_id);
+        //token config and mint of each token, the perpetual account data is kept
+        let migrate = |perpetual: &mut TestAccount, signer: &Pubkey, tokens: &[(u8, &str, Pubkey)]| {
+            let ix = instruction::migrate_perpetual(&roles.program_id, signer, tokens).unwrap();
+            let mut accounts = vec![TestAccount::system(), TestAccount::new(perpetual.data.clone(), roles.program_id)];
+            accounts.push(TestAccount::system());
+            while accounts.len() < ix.accounts.len() {
+                accounts.push(TestAccount::system());
+                accounts.push(mint_account());
+            }
+            let result = process(&ix, &mut accounts);
+            if result.is_ok() {
+                perpetual.data = accounts[1].data.clone();
+            }
+            (result, accounts)
+        };
+
+        //admin only, in map order
+        let (result, _) = migrate(&mut perpetual, &roles.gateway, &[(1, "BTC", btc_mint)]);
+        assert_eq!(result, Err(PerpError::IncorrectAdmin.into()));
+        let (result, _) = migrate(&mut perpetual, &roles.admin, &[(1, "ETH", eth_mint)]);
+        assert_eq!(result, Err(invalid_pda()));
+
+        //one token a transaction, the invalid symbols before it are dropped
+        let (result, accounts) = migrate(&mut perpetual, &roles.admin, &[(1, "BTC", btc_mint)]);
+        assert_eq!(result, Ok(()));
+        let token_config = TokenConfig::load(&accounts[3].data).unwrap();
+        assert_eq!(accounts[3].owner, roles.program_id);
+        assert_eq!((token_config.symbol.as_str(), token_config.mint, token_config.decimals), ("BTC", btc_mint, 6));
+        let left = LegacyPerpetual::unpack(&perpetual.data).unwrap();
+        assert_eq!(left.token_map.keys().cloned().collect::<Vec<_>>(), vec![token(1, "ETH"), token(2, &"S".repeat(40))]);
+        assert_eq!(Perpetual::load(&perpetual.data).err(), Some(PerpError::MigrationRequired.into()));
+
+        //the last valid token, the seed past 32 bytes is dropped without a panic, the account converts
+        let (result, accounts) = migrate(&mut perpetual, &roles.admin, &[(1, "ETH", eth_mint), (2, &"S".repeat(40), Pubkey::new_unique())]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(TokenConfig::load(&accounts[3].data).unwrap().mint, eth_mint);
+        assert_eq!(perpetual.data.len(), PERPETUAL_BYTES);
+        let migrated = Perpetual::load(&perpetual.data).unwrap();
+        assert_eq!((migrated.admin, migrated.gateway, migrated.bump_seed), (roles.admin, roles.gateway, bump_seed));
+        let ix = instruction::set_max_withdraw_validity(&roles.program_id, &roles.admin, 60).unwrap();
+        assert_eq!(process(&ix, &mut [TestAccount::system(), perpetual]), Ok(()));
+    }
+
+    //legacy user account with the last used withdraw id of account type 1
+    fn legacy_user_account(user: &Pubkey, last_withdrawid: u64) -> TestAccount {
+        let map: BTreeMap<u8, u64> = [(1, last_withdrawid)].into_iter().collect();
+        let map = map.try_to_vec().unwrap();
+        let mut data = vec![0; LEGACY_ACCOUNT_BYTES];
+        data[0] = 1;
+        data[1..33].copy_from_slice(user.as_ref());
+        data[33] = map.len() as u8;
+        data[34..34 + map.len()].copy_from_slice(&map);
+        TestAccount::new(data, Pubkey::default())
+    }
+
+    #[test]
+    fn migrate_user_account() {
+        let roles = Roles::new();
+        let user = Pubkey::new_unique();
+        let migrate = |payer: &Pubkey, mut account: TestAccount| {
+            account.owner = roles.program_id;
+            let ix = instruction::migrate_account(&roles.program_id, payer, &user).unwrap();
+            let mut accounts = [TestAccount::system(), roles.perpetual(), TestAccount::system(), account];
+            process(&ix, &mut accounts).map(|_| accounts[3].data.clone())
+        };
+
+        assert_eq!(migrate(&Pubkey::new_unique(), legacy_user_account(&user, 41)), Err(PerpError::Unauthorized.into()));
+        assert_eq!(migrate(&roles.gateway, legacy_user_account(&user, 41)), Err(PerpError::Unauthorized.into()));
+        //by the admin or the user
+        assert!(migrate(&roles.admin, legacy_user_account(&user, 41)).is_ok());
+        let data = migrate(&user, legacy_user_account(&user, 41)).unwrap();
+        assert_eq!(data.len(), ACCOUNT_BYTES);
+        let account = Account::load(&data).unwrap();
+        let (_, bump_seed) = find_account_address(&roles.program_id, &user);
+        assert_eq!((account.user, account.bump_seed), (user, bump_seed));
+        assert_eq!(account.withdraw_id[&1].base, 42);
+        //once
+        assert_eq!(migrate(&user, TestAccount::new(data.clone(), roles.program_id)), Err(ProgramError::AccountAlreadyInitialized));
+
+        //loads with the stored bump, ids up to the last used one are dead
+        let cancel = |withdrawid: u64| {
+            let ix = instruction::cancel_withdraw_id(&roles.program_id, &roles.gateway, &user, 1, withdrawid, None).unwrap();
+            process(&ix, &mut [TestAccount::system(), TestAccount::new(data.clone(), roles.program_id), roles.perpetual()])
+        };
+        assert_eq!(cancel(41), Err(PerpError::WithdrawIdFail.into()));
+        assert_eq!(cancel(42), Ok(()));
+    }
+
+    //a user withdrawing USDC and the accounts a withdraw of it reads, each signature
+    //recovers to its own key, made the active secp256k1 key or the signer set by sign
+    struct Withdrawal {
+        roles: Roles,
+        user: Pubkey,
+        user_token_account: Pubkey,
+        //a USDC token account of someone else
+        other_token_account: Pubkey,
+        program_token_account: Pubkey,
+        perpetual: Perpetual,
+        token_config: TokenConfig,
+        account: Vec<u8>,
+    }
+
+    impl Withdrawal {
+        fn new() -> Self {
+            let roles = Roles::new();
+            let user = Pubkey::new_unique();
+            let perpetual = Perpetual::load(&roles.perpetual().data).unwrap();
+            let mut token_config = TokenConfig::load(&roles.token_config(1, "USDC").data).unwrap();
+            token_config.mint = Pubkey::new_unique();
+            token_config.program_token_account = Pubkey::new_unique();
+            let account = roles.user_account(&user).data;
+            Withdrawal {
+                user,
+                user_token_account: Pubkey::new_unique(),
+                other_token_account: Pubkey::new_unique(),
+                program_token_account: token_config.program_token_account,
+                perpetual,
+                token_config,
+                account,
+                roles,
+            }
+        }
+
+        fn hash(&self, destination: &Pubkey, amount: u64, withdrawid: u64, timestamp: u64) -> [u8; 32] {
+            let (perpetual, _) = find_perpetual_address(&self.roles.program_id);
+            WithdrawMessage {
+                program_id: &self.roles.program_id,
+                perpetual: &perpetual,
+                destination,
+                user: &self.user,
+                account_type: 1,
+                symbol: "USDC",
+                amount,
+                withdrawid,
+                timestamp,
+            }
+            .hash_with(self.perpetual.signature_scheme)
+        }
+
+        //signature of a withdraw to `destination`, its key becomes the active secp256k1 key
+        fn sign(&mut self, destination: &Pubkey, amount: u64, withdrawid: u64, timestamp: u64) -> RecoverableSignature {
+            let (key, signature) = multisig_signers(&self.hash(destination, amount, withdrawid, timestamp), 1)[0];
+            self.perpetual.secp256k1_pubkey = key;
+            signature
+        }
+
+        //withdraw to the user token account signed by the active key
+        fn withdraw(&mut self, amount: u64, withdrawid: u64, timestamp: u64) -> Instruction {
+            let signature = self.sign(&self.user_token_account.clone(), amount, withdrawid, timestamp);
+            instruction::withdraw(
+                &self.roles.program_id,
+                &self.user,
+                &self.user_token_account,
+                &self.program_token_account,
+                1,
+                "USDC",
+                amount,
+                withdrawid,
+                timestamp,
+                signature.recovery_id,
+                signature.signature,
+            )
+            .unwrap()
+        }
+
+        //batch entries of the user, one signature each by a key of a signer set of threshold 1,
+        //one active key can't recover from the signatures of distinct messages
+        fn batch(&mut self, withdraws: &[(u64, u64, u64)]) -> Instruction {
+            let mut keys = Vec::new();
+            let entries = withdraws
+                .iter()
+                .map(|&(amount, withdrawid, timestamp)| {
+                    let hash = self.hash(&self.user_token_account, amount, withdrawid, timestamp);
+                    let (key, signature) = multisig_signers(&hash, 1)[0];
+                    keys.push(key);
+                    BatchWithdrawEntry { account_type: 1, symbol: "USDC".to_string(), amount, withdrawid, timestamp, signatures: vec![signature] }
+                })
+                .collect();
+            keys.sort();
+            keys.dedup();
+            self.perpetual.signers = keys;
+            self.perpetual.signer_threshold = 1;
+            self.token_config.multisig_amount = 1;
+            let entry_accounts = BatchWithdrawAccounts {
+                user: self.user,
+                user_token_account: self.user_token_account,
+                program_token_account: self.program_token_account,
+            };
+            instruction::batch_withdraw(&self.roles.program_id, &self.roles.gateway, entries, &vec![entry_accounts; withdraws.len()]).unwrap()
+        }
+
+        fn token_account(&self, owner: &Pubkey) -> TestAccount {
+            let token_account = TokenAccount {
+                mint: self.token_config.mint,
+                owner: *owner,
+                amount: 1_000_000,
+                state: spl_token::state::AccountState::Initialized,
+                ..TokenAccount::default()
+            };
+            let mut data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(token_account, &mut data).unwrap();
+            TestAccount::new(data, spl_token::id())
+        }
+
+        //accounts of `ix` by key, any other account is a system account,
+        //the state is kept only when it succeeds as the runtime drops the writes of a failed transaction
+        fn process(&mut self, ix: &Instruction) -> ProgramResult {
+            let program_id = self.roles.program_id;
+            let (account_key, _) = find_account_address(&program_id, &self.user);
+            let (perpetual_key, _) = find_perpetual_address(&program_id);
+            let (token_config_key, _) = find_token_address(&program_id, 1, "USDC");
+            let mut perpetual = vec![0; PERPETUAL_BYTES];
+            self.perpetual.store(&mut perpetual).unwrap();
+            let mut token_config = vec![0; TOKEN_CONFIG_BYTES];
+            self.token_config.store(&mut token_config).unwrap();
+            let mut accounts: Vec<TestAccount> = ix
+                .accounts
+                .iter()
+                .map(|meta| match meta.pubkey {
+                    key if key == self.user_token_account => self.token_account(&self.user),
+                    key if key == self.other_token_account => self.token_account(&Pubkey::new_unique()),
+                    key if key == account_key => TestAccount::new(self.account.clone(), program_id),
+                    key if key == perpetual_key => TestAccount::new(perpetual.clone(), program_id),
+                    key if key == token_config_key => TestAccount::new(token_config.clone(), program_id),
+                    _ => TestAccount::system(),
+                })
+                .collect();
+            process(ix, &mut accounts)?;
+            for (meta, account) in ix.accounts.iter().zip(accounts) {
+                match meta.pubkey {
+                    key if key == account_key => self.account = account.data,
+                    key if key == perpetual_key => self.perpetual = Perpetual::load(&account.data).unwrap(),
+                    key if key == token_config_key => self.token_config = TokenConfig::load(&account.data).unwrap(),
+                    _ => (),
+                }
+            }
+            Ok(())
+        }
+
+        fn withdraw_id(&self) -> WithdrawWindow {
+            Account::load(&self.account).unwrap().withdraw_id[&1]
+        }
+    }
+
+    #[test]
+    fn withdraw_signed_by_the_active_key() {
+        let mut test = Withdrawal::new();
+        let timestamp = NOW as u64 + 60;
+        let ix = test.withdraw(10, 1, timestamp);
+        assert_eq!(test.process(&ix), Ok(()));
+        assert_eq!(test.withdraw_id().base, 2);
+        //once
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawIdFail.into()));
+
+        //the signature covers the fields, a changed one recovers another key
+        let ix = test.withdraw(10, 2, timestamp);
+        let changed = |amount: u64, withdrawid: u64| {
+            let mut changed = ix.clone();
+            let mut instruction = PerpetualInstruction::unpack(&ix.data).unwrap();
+            if let PerpetualInstruction::Withdraw { amount: a, withdrawid: w, .. } = &mut instruction {
+                (*a, *w) = (amount, withdrawid);
+            }
+            changed.data = instruction.pack().unwrap();
+            changed
+        };
+        assert_eq!(test.process(&changed(11, 2)), Err(PerpError::SignatureMismatch.into()));
+        assert_eq!(test.process(&changed(10, 3)), Err(PerpError::SignatureMismatch.into()));
+        //a key that is not the active one
+        let active = test.perpetual.secp256k1_pubkey;
+        test.perpetual.secp256k1_pubkey = [1; 64];
+        assert_eq!(test.process(&ix), Err(PerpError::SignatureMismatch.into()));
+        test.perpetual.secp256k1_pubkey = active;
+        //the failed ones burnt nothing
+        assert_eq!(test.withdraw_id().base, 2);
+        assert_eq!(test.process(&ix), Ok(()));
+        assert_eq!(test.withdraw_id().base, 3);
+    }
+
+    #[test]
+    fn batch_withdraw_all_or_nothing() {
+        let mut test = Withdrawal::new();
+        let timestamp = NOW as u64 + 60;
+        let ix = test.batch(&[(10, 1, timestamp), (20, 2, timestamp), (30, 4, timestamp)]);
+        assert_eq!(test.process(&ix), Ok(()));
+        let window = test.withdraw_id();
+        assert_eq!(window.base, 3);
+        assert!(window.is_used(4) && !window.is_used(5));
+
+        //any failing entry fails the batch, the runtime then drops the writes of the earlier ones
+        let ix = test.batch(&[(10, 3, timestamp), (10, 5, timestamp), (10, 4, timestamp)]);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawIdFail.into()));
+        let ix = test.batch(&[(10, 3, timestamp), (10, 5, NOW as u64)]);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawExpired.into()));
+        //an entry changed after signing
+        let mut ix = test.batch(&[(10, 3, timestamp), (10, 5, timestamp)]);
+        let mut entries = match PerpetualInstruction::unpack(&ix.data).unwrap() {
+            PerpetualInstruction::BatchWithdraw { entries } => entries,
+            _ => unreachable!(),
+        };
+        entries[1].amount = 11;
+        ix.data = PerpetualInstruction::BatchWithdraw { entries }.pack().unwrap();
+        assert_eq!(test.process(&ix), Err(PerpError::SignerNotInSet.into()));
+        assert_eq!(test.withdraw_id(), window);
+
+        let ix = test.batch(&[(10, 3, timestamp), (10, 5, timestamp)]);
+        assert_eq!(test.process(&ix), Ok(()));
+        assert_eq!(test.withdraw_id().base, 6);
+    }
+
+    #[test]
+    fn withdraw_timestamp_window() {
+        let mut test = Withdrawal::new();
+        let now = NOW as u64;
+        let validity = DEFAULT_MAX_WITHDRAW_VALIDITY;
+        //stale, now is already too late
+        for timestamp in [0, now - 1, now] {
+            let ix = test.withdraw(10, 1, timestamp);
+            assert_eq!(test.process(&ix), Err(PerpError::WithdrawExpired.into()));
+        }
+        //past max_withdraw_validity from now
+        for timestamp in [now + validity + 1, u64::MAX] {
+            let ix = test.withdraw(10, 1, timestamp);
+            assert_eq!(test.process(&ix), Err(PerpError::WithdrawValidityTooLong.into()));
+        }
+        //in the future, up to the boundary
+        let ix = test.withdraw(10, 1, now + 1);
+        assert_eq!(test.process(&ix), Ok(()));
+        let ix = test.withdraw(10, 2, now + validity);
+        assert_eq!(test.process(&ix), Ok(()));
+
+        //set by the admin only
+        let ix = instruction::set_max_withdraw_validity(&test.roles.program_id, &test.roles.gateway, 60).unwrap();
+        assert_eq!(test.process(&ix), Err(PerpError::IncorrectAdmin.into()));
+        let ix = instruction::set_max_withdraw_validity(&test.roles.program_id, &test.roles.admin, 60).unwrap();
+        assert_eq!(test.process(&ix), Ok(()));
+        assert_eq!(test.perpetual.max_withdraw_validity, 60);
+        let ix = test.withdraw(10, 3, now + 61);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawValidityTooLong.into()));
+        let ix = test.withdraw(10, 3, now + 60);
+        assert_eq!(test.process(&ix), Ok(()));
+
+        //0 is no limit, a timestamp still has to be in the future
+        let ix = instruction::set_max_withdraw_validity(&test.roles.program_id, &test.roles.admin, 0).unwrap();
+        assert_eq!(test.process(&ix), Ok(()));
+        let ix = test.withdraw(10, 4, now);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawExpired.into()));
+        let ix = test.withdraw(10, 4, u64::MAX);
+        assert_eq!(test.process(&ix), Ok(()));
+    }
+
+    #[test]
+    fn withdraw_max_amount_and_cosign() {
+        let mut test = Withdrawal::new();
+        let timestamp = NOW as u64 + 60;
+        let (program_id, admin, gateway) = (test.roles.program_id, test.roles.admin, test.roles.gateway);
+        let ix = instruction::set_withdraw_limits(&program_id, &admin, 1, "USDC", 100, 0, 50).unwrap();
+        assert_eq!(test.process(&ix), Ok(()));
+
+        //over the max of one withdraw, even co-signed
+        let mut ix = test.withdraw(101, 1, timestamp);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawAmountTooLarge.into()));
+        instruction::add_withdraw_cosigner(&mut ix, &admin);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawAmountTooLarge.into()));
+
+        //below cosign_amount the user alone
+        let ix = test.withdraw(49, 1, timestamp);
+        assert_eq!(test.process(&ix), Ok(()));
+        //from cosign_amount on, the admin or the gateway co-signs
+        for (amount, withdrawid) in [(50, 2), (100, 3)] {
+            let ix = test.withdraw(amount, withdrawid, timestamp);
+            assert_eq!(test.process(&ix), Err(PerpError::CosignatureRequired.into()));
+        }
+        let mut ix = test.withdraw(50, 2, timestamp);
+        instruction::add_withdraw_cosigner(&mut ix, &admin);
+        assert_eq!(test.process(&ix), Ok(()));
+        let mut ix = test.withdraw(100, 3, timestamp);
+        instruction::add_withdraw_cosigner(&mut ix, &gateway);
+        assert_eq!(test.process(&ix), Ok(()));
+        //any other co-signer, or one that didn't sign
+        let mut ix = test.withdraw(100, 4, timestamp);
+        instruction::add_withdraw_cosigner(&mut ix, &test.roles.guardian.clone());
+        assert_eq!(test.process(&ix), Err(PerpError::Unauthorized.into()));
+        let mut ix = test.withdraw(100, 4, timestamp);
+        ix.accounts.push(AccountMeta::new_readonly(admin, false));
+        assert_eq!(test.process(&ix), Err(ProgramError::MissingRequiredSignature));
+        assert_eq!(test.withdraw_id().base, 4);
+    }
+
+    #[test]
+    fn relayed_withdraw_only_to_the_user() {
+        let mut test = Withdrawal::new();
+        let timestamp = NOW as u64 + 60;
+        let (program_id, gateway) = (test.roles.program_id, test.roles.gateway);
+        let (user_token_account, other_token_account) = (test.user_token_account, test.other_token_account);
+        let relayed = |test: &mut Withdrawal, destination: &Pubkey, amount: u64, withdrawid: u64| {
+            let signature = test.sign(destination, amount, withdrawid, timestamp);
+            instruction::relayed_withdraw(
+                &program_id,
+                &gateway,
+                &test.user,
+                destination,
+                &test.program_token_account,
+                1,
+                "USDC",
+                amount,
+                withdrawid,
+                timestamp,
+                vec![signature],
+            )
+            .unwrap()
+        };
+
+        //the gateway signs and pays, the user doesn't sign
+        let ix = relayed(&mut test, &user_token_account, 10, 1);
+        assert!(ix.accounts.iter().all(|meta| !meta.is_signer || meta.pubkey == gateway));
+        assert_eq!(test.process(&ix), Ok(()));
+        assert_eq!(test.withdraw_id().base, 2);
+        assert_eq!(test.process(&ix), Err(PerpError::WithdrawIdFail.into()));
+
+        //a token account of someone else, even with a signature over it
+        let ix = relayed(&mut test, &other_token_account, 10, 2);
+        assert_eq!(test.process(&ix), Err(ProgramError::InvalidAccountData));
+        assert_eq!(test.withdraw_id().base, 2);
+
+        //the relaying gateway is the co-signer
+        let ix = instruction::set_withdraw_limits(&program_id, &test.roles.admin, 1, "USDC", 0, 0, 50).unwrap();
+        assert_eq!(test.process(&ix), Ok(()));
+        let ix = relayed(&mut test, &user_token_account, 50, 2);
+        assert_eq!(test.process(&ix), Ok(()));
+    }
+
+    #[test]
+    fn paused_deposit_and_withdraw() {
+        let mut test = Withdrawal::new();
+        let timestamp = NOW as u64 + 60;
+        let (program_id, admin) = (test.roles.program_id, test.roles.admin);
+        let usdc = TypeSymbol { account_type: 1, symbol: "USDC".to_string() };
+        let deposit = instruction::deposit(&program_id, &test.user, &test.user_token_account, &test.program_token_account, 1, "USDC", 10).unwrap();
+        let mut withdrawid = 0;
+        //deposit and withdraw results under the global and token flags
+        let mut run = |test: &mut Withdrawal, global: u8, token: u8| {
+            let ix = instruction::set_pause(&program_id, &admin, None, global).unwrap();
+            assert_eq!(test.process(&ix), Ok(()));
+            let ix = instruction::set_pause(&program_id, &admin, Some(usdc.clone()), token).unwrap();
+            assert_eq!(test.process(&ix), Ok(()));
+            withdrawid += 1;
+            let ix = test.withdraw(10, withdrawid, timestamp);
+            (test.process(&deposit), test.process(&ix))
+        };
+        let paused = Err(PerpError::Paused.into());
+
+        assert_eq!(run(&mut test, 0, 0), (Ok(()), Ok(())));
+        assert_eq!(run(&mut test, PAUSE_DEPOSIT, 0), (paused.clone(), Ok(())));
+        assert_eq!(run(&mut test, PAUSE_WITHDRAW, 0), (Ok(()), paused.clone()));
+        assert_eq!(run(&mut test, PAUSE_ALL, 0), (paused.clone(), paused.clone()));
+        assert_eq!(run(&mut test, 0, PAUSE_DEPOSIT), (paused.clone(), Ok(())));
+        assert_eq!(run(&mut test, 0, PAUSE_WITHDRAW), (Ok(()), paused.clone()));
+        assert_eq!(run(&mut test, 0, PAUSE_ALL), (paused.clone(), paused.clone()));
+        //either flag pauses
+        assert_eq!(run(&mut test, PAUSE_DEPOSIT, PAUSE_WITHDRAW), (paused.clone(), paused));
+        assert_eq!(run(&mut test, 0, 0), (Ok(()), Ok(())));
+    }
+
+    #[test]
+    fn cancel_withdraw_id_signed() {
+        let mut test = Withdrawal::new();
+        let program_id = test.roles.program_id;
+        let (perpetual, _) = find_perpetual_address(&program_id);
+        let user = test.user;
+        //any fee payer, authorized by the signature
+        let payer = Pubkey::new_unique();
+        let hash = |withdrawid: u64, scheme: SignatureScheme| {
+            CancelWithdrawMessage { program_id: &program_id, perpetual: &perpetual, user: &user, account_type: 1, withdrawid }.hash_with(scheme)
+        };
+        let cancel = |withdrawid: u64, signature: RecoverableSignature| {
+            instruction::cancel_withdraw_id(&program_id, &payer, &user, 1, withdrawid, Some(signature)).unwrap()
+        };
+
+        //by the active key
+        let (key, signature) = multisig_signers(&hash(2, SignatureScheme::Keccak), 1)[0];
+        test.perpetual.secp256k1_pubkey = key;
+        assert_eq!(test.process(&cancel(2, signature)), Ok(()));
+        assert!(test.withdraw_id().is_used(2));
+        assert_eq!(test.process(&cancel(2, signature)), Err(PerpError::WithdrawIdFail.into()));
+
+        //over another withdraw id, by another key, or hashed with another scheme
+        assert_eq!(test.process(&cancel(3, signature)), Err(PerpError::SignatureMismatch.into()));
+        let (_, other) = multisig_signers(&hash(3, SignatureScheme::Keccak), 2)[1];
+        assert_eq!(test.process(&cancel(3, other)), Err(PerpError::SignatureMismatch.into()));
+        let (key, signature) = multisig_signers(&hash(3, SignatureScheme::Eip712), 1)[0];
+        test.perpetual.secp256k1_pubkey = key;
+        assert_eq!(test.process(&cancel(3, signature)), Err(PerpError::SignatureMismatch.into()));
+        test.perpetual.signature_scheme = SignatureScheme::Eip712;
+        assert_eq!(test.process(&cancel(3, signature)), Ok(()));
+        let window = test.withdraw_id();
+        assert!(window.is_used(3) && !window.is_used(4) && window.base == 1);
+    }
+
+    #[test]
+    fn removed_token_is_closed() {
+        let roles = Roles::new();
+        let (perpetual, _) = find_perpetual_address(&roles.program_id);
+        let (token_config_key, _) = find_token_address(&roles.program_id, 1, "USDC");
+        let program_token_account = Pubkey::new_unique();
+        let ix = instruction::set_token_map(&roles.program_id, &roles.admin, &program_token_account, &Pubkey::new_unique(), 1, "USDC").unwrap();
+        let load = |token_config: &mut TestAccount| {
+            let info = AccountInfo::new(
+                &token_config_key,
+                false,
+                false,
+                &mut token_config.lamports,
+                &mut token_config.data,
+                &token_config.owner,
+                false,
+                0,
+            );
+            Processor::load_token_config(&info, 1, "USDC", &roles.program_id)
+        };
+
+        //a program token account not owned by the token program removes the token
+        let mut accounts = [
+            TestAccount::system(),
+            roles.perpetual(),
+            TestAccount::system(),
+            roles.token_config(1, "USDC"),
+            TestAccount::system(),
+            mint_account(),
+        ];
+        let lamports = accounts[0].lamports + accounts[3].lamports;
+        assert_eq!(process(&ix, &mut accounts), Ok(()));
+        let [admin, _, _, token_config, _, _] = &mut accounts;
+        assert_eq!(admin.lamports, lamports);
+        assert_eq!((token_config.lamports, token_config.data.len()), (0, 0));
+        assert_eq!(token_config.owner, solana_program::system_program::id());
+        assert_eq!(load(token_config).err(), Some(ProgramError::UninitializedAccount));
+
+        //added again like a new token
+        let program_token = TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: perpetual,
+            state: spl_token::state::AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(program_token, &mut data).unwrap();
+        let mut accounts = [
+            TestAccount::system(),
+            roles.perpetual(),
+            TestAccount::new(data, spl_token::id()),
+            TestAccount { lamports: 0, ..TestAccount::system() },
+            TestAccount::system(),
+            mint_account(),
+        ];
+        let ix = instruction::set_token_map(&roles.program_id, &roles.admin, &program_token_account, &program_token.mint, 1, "USDC").unwrap();
+        assert_eq!(process(&ix, &mut accounts), Ok(()));
+        let token_config = load(&mut accounts[3]).unwrap();
+        assert_eq!((token_config.mint, token_config.program_token_account), (program_token.mint, program_token_account));
+    }
+}
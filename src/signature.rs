@@ -0,0 +1,476 @@
+//yt: withdraw signature message, pure functions shared by the program and the off-chain signer
+use solana_program::{
+    keccak,
+    pubkey::Pubkey,
+    secp256k1_recover::{secp256k1_recover, Secp256k1RecoverError},
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use crate::error::PerpError;
+
+/// Version of the withdraw message layout
+pub const WITHDRAW_MESSAGE_VERSION: u8 = 1;
+/// Prefix of the withdraw domain separator
+pub const WITHDRAW_DOMAIN_PREFIX: &[u8] = b"aboard-perpetual-withdraw";
+/// Prefix of the withdraw id cancel domain separator
+pub const CANCEL_WITHDRAW_DOMAIN_PREFIX: &[u8] = b"aboard-perpetual-cancel-withdraw";
+
+/// Cluster the program is built for, a devnet signature is invalid on mainnet
+#[cfg(feature = "devnet")]
+pub const CLUSTER_TAG: &[u8] = b"devnet";
+#[cfg(all(feature = "testnet", not(feature = "devnet")))]
+pub const CLUSTER_TAG: &[u8] = b"testnet";
+#[cfg(not(any(feature = "devnet", feature = "testnet")))]
+pub const CLUSTER_TAG: &[u8] = b"mainnet-beta";
+
+/// EIP-712 chainId of the cluster, the solana cluster ids 101, 102, 103
+#[cfg(feature = "devnet")]
+pub const EIP712_CHAIN_ID: u64 = 103;
+#[cfg(all(feature = "testnet", not(feature = "devnet")))]
+pub const EIP712_CHAIN_ID: u64 = 102;
+#[cfg(not(any(feature = "devnet", feature = "testnet")))]
+pub const EIP712_CHAIN_ID: u64 = 101;
+
+/// EIP-712 domain type, salt is keccak(program id || perpetual)
+pub const EIP712_DOMAIN_TYPE: &[u8] =
+    b"EIP712Domain(string name,string version,uint256 chainId,bytes32 salt)";
+/// EIP-712 domain name
+pub const EIP712_NAME: &[u8] = b"Aboard Perpetual";
+/// EIP-712 domain version
+pub const EIP712_VERSION: &[u8] = b"1";
+/// EIP-712 Withdraw struct type, pubkeys are bytes32
+pub const EIP712_WITHDRAW_TYPE: &[u8] =
+    b"Withdraw(bytes32 user,bytes32 destination,uint8 accountType,string symbol,uint64 amount,uint64 withdrawId,uint64 timestamp)";
+/// EIP-712 CancelWithdraw struct type
+pub const EIP712_CANCEL_WITHDRAW_TYPE: &[u8] =
+    b"CancelWithdraw(bytes32 user,uint8 accountType,uint64 withdrawId)";
+
+/// secp256k1 group order n / 2, big endian. s above it is the malleated n - s form
+pub const SECP256K1_HALF_ORDER: [u8; 32] = [
+    0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x5D, 0x57, 0x6E, 0x73, 0x57, 0xA4, 0x50, 0x1D, 0xDF, 0xE9, 0x2F, 0x46, 0x68, 0x1B, 0x20, 0xA0,
+];
+/// Largest secp256k1 recovery id, ethereum style 27/28 must be converted to 0/1
+pub const MAX_RECOVERY_ID: u8 = 3;
+
+/// Recovers the secp256k1 key of a low-S signature (r || s) over `hash`
+pub fn recover_signer(hash: &[u8; 32], recovery_id: u8, signature: &[u8; 64]) -> Result<[u8; 64], PerpError> {
+    if recovery_id > MAX_RECOVERY_ID {
+        return Err(PerpError::InvalidRecoveryId);
+    }
+    //big endian compare
+    let s = &signature[32..];
+    if s > &SECP256K1_HALF_ORDER[..] {
+        return Err(PerpError::HighSSignature);
+    }
+    let pubkey = secp256k1_recover(hash, recovery_id, signature).map_err(|e| match e {
+        Secp256k1RecoverError::InvalidHash => PerpError::InvalidSignatureHash,
+        Secp256k1RecoverError::InvalidRecoveryId => PerpError::InvalidRecoveryId,
+        Secp256k1RecoverError::InvalidSignature => PerpError::InvalidSignature,
+    })?;
+    Ok(pubkey.to_bytes())
+}
+
+/// How the withdraw message is hashed before secp256k1 signing, set per perpetual account
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// WithdrawMessage::hash
+    Keccak,
+    /// WithdrawMessage::eip712_hash, for ethereum signer tooling
+    Eip712,
+}
+
+impl SignatureScheme {
+    /// Decodes the scheme byte of the perpetual account
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Keccak),
+            1 => Some(Self::Eip712),
+            _ => None,
+        }
+    }
+}
+
+//abi encoding of a uint, 32 bytes big endian
+fn abi_uint(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+/// EIP-712 domain separator of the program and perpetual account on this cluster
+pub fn eip712_domain_separator(program_id: &Pubkey, perpetual: &Pubkey) -> [u8; 32] {
+    let salt = keccak::hashv(&[program_id.as_ref(), perpetual.as_ref()]);
+    keccak::hashv(&[
+        &keccak::hash(EIP712_DOMAIN_TYPE).to_bytes(),
+        &keccak::hash(EIP712_NAME).to_bytes(),
+        &keccak::hash(EIP712_VERSION).to_bytes(),
+        &abi_uint(EIP712_CHAIN_ID),
+        &salt.to_bytes(),
+    ])
+    .to_bytes()
+}
+
+//EIP-712 digest: keccak(0x19 0x01 || domain separator || hashStruct)
+fn eip712_digest(domain_separator: &[u8; 32], struct_hash: &[u8; 32]) -> [u8; 32] {
+    keccak::hashv(&[&[0x19, 0x01], domain_separator, struct_hash]).to_bytes()
+}
+
+/// Fields signed by the secp256k1 signer to authorize a withdraw
+#[derive(Debug, Clone, Copy)]
+pub struct WithdrawMessage<'a> {
+    /// The perpetual program
+    pub program_id: &'a Pubkey,
+    /// The perpetual account(config)
+    pub perpetual: &'a Pubkey,
+    /// The user token account receiving the withdraw
+    pub destination: &'a Pubkey,
+    /// The user of the perpetual user account
+    pub user: &'a Pubkey,
+    /// account type
+    pub account_type: u8,
+    /// token symbol
+    pub symbol: &'a str,
+    /// withdraw amount
+    pub amount: u64,
+    /// withdraw id
+    pub withdrawid: u64,
+    /// time
+    pub timestamp: u64,
+}
+
+impl WithdrawMessage<'_> {
+    /// keccak(prefix || version || cluster || program id || perpetual || destination),
+    /// binds a signature to one deployment, one cluster and one token account
+    pub fn domain_separator(&self) -> [u8; 32] {
+        keccak::hashv(&[
+            WITHDRAW_DOMAIN_PREFIX,
+            &[WITHDRAW_MESSAGE_VERSION],
+            &[CLUSTER_TAG.len() as u8],
+            CLUSTER_TAG,
+            self.program_id.as_ref(),
+            self.perpetual.as_ref(),
+            self.destination.as_ref(),
+        ])
+        .to_bytes()
+    }
+
+    /// keccak(domain separator || user || account type || symbol || amount || withdrawid || timestamp),
+    /// symbol is u8 length prefixed, numbers are big endian
+    pub fn hash(&self) -> [u8; 32] {
+        keccak::hashv(&[
+            &self.domain_separator(),
+            self.user.as_ref(),
+            &[self.account_type],
+            &[self.symbol.len() as u8],
+            self.symbol.as_bytes(),
+            &self.amount.to_be_bytes(),
+            &self.withdrawid.to_be_bytes(),
+            &self.timestamp.to_be_bytes(),
+        ])
+        .to_bytes()
+    }
+
+    /// Hash of the message under `scheme`
+    pub fn hash_with(&self, scheme: SignatureScheme) -> [u8; 32] {
+        match scheme {
+            SignatureScheme::Keccak => self.hash(),
+            SignatureScheme::Eip712 => self.eip712_hash(),
+        }
+    }
+
+    /// EIP-712 hashStruct of the Withdraw
+    pub fn eip712_struct_hash(&self) -> [u8; 32] {
+        keccak::hashv(&[
+            &keccak::hash(EIP712_WITHDRAW_TYPE).to_bytes(),
+            self.user.as_ref(),
+            self.destination.as_ref(),
+            &abi_uint(self.account_type as u64),
+            &keccak::hash(self.symbol.as_bytes()).to_bytes(),
+            &abi_uint(self.amount),
+            &abi_uint(self.withdrawid),
+            &abi_uint(self.timestamp),
+        ])
+        .to_bytes()
+    }
+
+    /// EIP-712 digest: keccak(0x19 0x01 || domain separator || hashStruct)
+    pub fn eip712_hash(&self) -> [u8; 32] {
+        let domain_separator = eip712_domain_separator(self.program_id, self.perpetual);
+        eip712_digest(&domain_separator, &self.eip712_struct_hash())
+    }
+}
+
+/// Fields signed by the secp256k1 signer to burn an issued, unsubmitted withdraw id
+#[derive(Debug, Clone, Copy)]
+pub struct CancelWithdrawMessage<'a> {
+    /// The perpetual program
+    pub program_id: &'a Pubkey,
+    /// The perpetual account(config)
+    pub perpetual: &'a Pubkey,
+    /// The user of the perpetual user account
+    pub user: &'a Pubkey,
+    /// account type
+    pub account_type: u8,
+    /// withdraw id to burn
+    pub withdrawid: u64,
+}
+
+impl CancelWithdrawMessage<'_> {
+    /// keccak(prefix || version || cluster || program id || perpetual || user || account type || withdrawid)
+    pub fn hash(&self) -> [u8; 32] {
+        keccak::hashv(&[
+            CANCEL_WITHDRAW_DOMAIN_PREFIX,
+            &[WITHDRAW_MESSAGE_VERSION],
+            &[CLUSTER_TAG.len() as u8],
+            CLUSTER_TAG,
+            self.program_id.as_ref(),
+            self.perpetual.as_ref(),
+            self.user.as_ref(),
+            &[self.account_type],
+            &self.withdrawid.to_be_bytes(),
+        ])
+        .to_bytes()
+    }
+
+    /// EIP-712 digest of the CancelWithdraw struct
+    pub fn eip712_hash(&self) -> [u8; 32] {
+        let struct_hash = keccak::hashv(&[
+            &keccak::hash(EIP712_CANCEL_WITHDRAW_TYPE).to_bytes(),
+            self.user.as_ref(),
+            &abi_uint(self.account_type as u64),
+            &abi_uint(self.withdrawid),
+        ])
+        .to_bytes();
+        let domain_separator = eip712_domain_separator(self.program_id, self.perpetual);
+        eip712_digest(&domain_separator, &struct_hash)
+    }
+
+    /// Hash of the message under `scheme`
+    pub fn hash_with(&self, scheme: SignatureScheme) -> [u8; 32] {
+        match scheme {
+            SignatureScheme::Keccak => self.hash(),
+            SignatureScheme::Eip712 => self.eip712_hash(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //secp256k1 signature of HASH by the secret key [7; 32], recovery id 0
+    const HASH: [u8; 32] = [0x11; 32];
+    const R: [u8; 32] = [
+        37, 109, 136, 24, 188, 245, 217, 215, 195, 61, 61, 7, 125, 154, 16, 133,
+        33, 198, 245, 192, 223, 66, 56, 24, 209, 219, 252, 118, 17, 179, 52, 142,
+    ];
+    const S: [u8; 32] = [
+        125, 41, 53, 80, 49, 106, 165, 9, 254, 100, 179, 233, 201, 5, 155, 66,
+        150, 149, 5, 104, 250, 48, 221, 57, 8, 203, 44, 152, 185, 45, 130, 105,
+    ];
+    const PUBKEY: [u8; 64] = [
+        152, 156, 11, 118, 203, 86, 57, 113, 253, 201, 190, 243, 30, 192, 108, 53,
+        96, 243, 36, 157, 110, 233, 229, 216, 60, 87, 98, 85, 150, 224, 95, 111,
+        99, 31, 77, 5, 179, 174, 81, 135, 118, 238, 8, 117, 90, 119, 3, 230,
+        75, 46, 188, 50, 84, 117, 4, 222, 11, 85, 161, 66, 212, 236, 223, 128,
+    ];
+    //secp256k1 group order n
+    const ORDER: [u8; 32] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+        0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+    ];
+
+    fn signature(r: &[u8; 32], s: &[u8; 32]) -> [u8; 64] {
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(r);
+        signature[32..].copy_from_slice(s);
+        signature
+    }
+
+    //n - s, big endian
+    fn negate(s: &[u8; 32]) -> [u8; 32] {
+        let mut result = [0u8; 32];
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let mut digit = ORDER[i] as i16 - s[i] as i16 - borrow;
+            borrow = (digit < 0) as i16;
+            if digit < 0 {
+                digit += 256;
+            }
+            result[i] = digit as u8;
+        }
+        result
+    }
+
+    #[test]
+    fn recovers_low_s_vector() {
+        assert!(S <= SECP256K1_HALF_ORDER);
+        assert_eq!(recover_signer(&HASH, 0, &signature(&R, &S)), Ok(PUBKEY));
+        //the other recovery id is a different key
+        assert_ne!(recover_signer(&HASH, 1, &signature(&R, &S)), Ok(PUBKEY));
+    }
+
+    #[test]
+    fn rejects_malleated_twin() {
+        let high_s = negate(&S);
+        assert!(high_s > SECP256K1_HALF_ORDER);
+        for recovery_id in 0..=MAX_RECOVERY_ID {
+            assert_eq!(recover_signer(&HASH, recovery_id, &signature(&R, &high_s)), Err(PerpError::HighSSignature));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_recovery_id() {
+        for recovery_id in [MAX_RECOVERY_ID + 1, 27, 28, u8::MAX] {
+            assert_eq!(recover_signer(&HASH, recovery_id, &signature(&R, &S)), Err(PerpError::InvalidRecoveryId));
+        }
+    }
+
+    #[test]
+    fn rejects_zero_signature() {
+        assert_eq!(recover_signer(&HASH, 0, &[0; 64]), Err(PerpError::InvalidSignature));
+        assert_eq!(recover_signer(&HASH, 0, &signature(&[0; 32], &S)), Err(PerpError::InvalidSignature));
+        assert_eq!(recover_signer(&HASH, 0, &signature(&R, &[0; 32])), Err(PerpError::InvalidSignature));
+    }
+
+    fn hex(s: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    //fixed messages of the known answer tests
+    const PROGRAM_ID: Pubkey = Pubkey::new_from_array([1; 32]);
+    const PERPETUAL: Pubkey = Pubkey::new_from_array([2; 32]);
+    const DESTINATION: Pubkey = Pubkey::new_from_array([3; 32]);
+    const USER: Pubkey = Pubkey::new_from_array([4; 32]);
+
+    fn withdraw_message() -> WithdrawMessage<'static> {
+        WithdrawMessage {
+            program_id: &PROGRAM_ID,
+            perpetual: &PERPETUAL,
+            destination: &DESTINATION,
+            user: &USER,
+            account_type: 1,
+            symbol: "USDC",
+            amount: 1_000_000,
+            withdrawid: 42,
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    fn cancel_message() -> CancelWithdrawMessage<'static> {
+        CancelWithdrawMessage {
+            program_id: &PROGRAM_ID,
+            perpetual: &PERPETUAL,
+            user: &USER,
+            account_type: 1,
+            withdrawid: 42,
+        }
+    }
+
+    //domain separator, withdraw and cancel hashes of the messages above, keccak-256
+    //of the documented byte layout computed outside this crate
+    #[cfg(not(any(feature = "devnet", feature = "testnet")))]
+    const KECCAK_VECTORS: [&str; 3] = [
+        "b76eef7dacf1c68e821d5e269f20da1851e2e8e0ff42775f1f6c278aa961a279",
+        "397c1acb66d0825f2fe8e35264c0258c15d3941d7660e4969b332f38166270f9",
+        "2ecc33f71768602528d76372eb6fbd5f2219ee9ca49667bc70304d0ca2616860",
+    ];
+    #[cfg(all(feature = "testnet", not(feature = "devnet")))]
+    const KECCAK_VECTORS: [&str; 3] = [
+        "ef93e62b74cde3f850944984da8a1a64cadc192a0213be7f150cba2a11aff7b3",
+        "7f4df8612e0df1b53e44053ee863be424d31a13ce196f96a6fb85f08494f942e",
+        "ed2a98092219af7d11c1363dab4796f6119343075e6df8e547b1e22d56ddb84c",
+    ];
+    #[cfg(feature = "devnet")]
+    const KECCAK_VECTORS: [&str; 3] = [
+        "fef0864bdcf515004c504909c35bb73f984cde2990b2dedcd954b93504708003",
+        "e6a5fb6a8dfcfaf88c8c206ea475f81dc48af180d16cd890d878b7152dea7838",
+        "efed48cd621ab5ab8c25c601990bc4955cf472f7741da4b17b6a2bf7db9d8ad6",
+    ];
+
+    #[test]
+    fn keccak_known_answer() {
+        let [domain, withdraw, cancel] = KECCAK_VECTORS.map(hex);
+        assert_eq!(withdraw_message().domain_separator(), domain);
+        assert_eq!(withdraw_message().hash(), withdraw);
+        assert_eq!(withdraw_message().hash_with(SignatureScheme::Keccak), withdraw);
+        assert_eq!(cancel_message().hash(), cancel);
+        assert_eq!(cancel_message().hash_with(SignatureScheme::Keccak), cancel);
+    }
+
+    //domain separator, Withdraw and CancelWithdraw digests of the messages above from a
+    //generic EIP-712 typed data encoder (eth_signTypedData_v4), checked with the Ether Mail vector
+    #[cfg(not(any(feature = "devnet", feature = "testnet")))]
+    const EIP712_VECTORS: [&str; 3] = [
+        "396c5e23b31e321a6e4bcaa908c57514d541879b644d31e63364cff6dbf74206",
+        "70922373ad43664b7980031e8e4610bad9edefab8b5799f98ab8edc443e043f2",
+        "f2bff5049471f93367fc27dbeb939dfd6f45fff2e135533b9318fb4d056ed0af",
+    ];
+    #[cfg(all(feature = "testnet", not(feature = "devnet")))]
+    const EIP712_VECTORS: [&str; 3] = [
+        "79212872f4c98ab3bbc65cf334f396c67e6581976c36f0ab34204bc2a0d3a78f",
+        "6a05e584db75a318b744a2e1ee8789e4736b9d188564fed38b916271d9f30137",
+        "d391fa204ee7a62b0b6443b1f20a73ee52cc370c0da79d4af7671855285199eb",
+    ];
+    #[cfg(feature = "devnet")]
+    const EIP712_VECTORS: [&str; 3] = [
+        "7c2c318b0d461719a7579406c97648379be823fa2b771e05d6ef5077ac1b59af",
+        "fd257472ed91e4e2f6e074af7ce12caf55ecf07b9de726f2ef837c7c05186950",
+        "9cc4afae960dd08db5138068467ad7fa658a8b49bbb789ee1d52402a690d6ebb",
+    ];
+
+    #[test]
+    fn eip712_known_answer() {
+        let [domain, withdraw, cancel] = EIP712_VECTORS.map(hex);
+        assert_eq!(eip712_domain_separator(&PROGRAM_ID, &PERPETUAL), domain);
+        //the struct hash doesn't depend on the cluster
+        assert_eq!(
+            withdraw_message().eip712_struct_hash(),
+            hex("8fa069c9c3f85af73b3e26f8f9d435983eb210d783fac8fbe7519207b46a8e95")
+        );
+        assert_eq!(withdraw_message().eip712_hash(), withdraw);
+        assert_eq!(withdraw_message().hash_with(SignatureScheme::Eip712), withdraw);
+        assert_eq!(cancel_message().eip712_hash(), cancel);
+        assert_eq!(cancel_message().hash_with(SignatureScheme::Eip712), cancel);
+    }
+
+    //the Ether Mail example of the EIP-712 specification through the same encoding helpers
+    #[test]
+    fn eip712_spec_vector() {
+        let address = |s: &str| {
+            let mut word = [0u8; 32];
+            word[12..].copy_from_slice(&hex(&format!("{:0>64}", s))[12..]);
+            word
+        };
+        let domain_type = b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+        let domain_separator = keccak::hashv(&[
+            &keccak::hash(domain_type).to_bytes(),
+            &keccak::hash(b"Ether Mail").to_bytes(),
+            &keccak::hash(b"1").to_bytes(),
+            &abi_uint(1),
+            &address("CcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"),
+        ])
+        .to_bytes();
+        assert_eq!(domain_separator, hex("f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f"));
+        let person_type_hash = keccak::hash(b"Person(string name,address wallet)").to_bytes();
+        let person = |name: &[u8], wallet: &str| {
+            keccak::hashv(&[&person_type_hash, &keccak::hash(name).to_bytes(), &address(wallet)]).to_bytes()
+        };
+        let mail = keccak::hashv(&[
+            &keccak::hash(b"Mail(Person from,Person to,string contents)Person(string name,address wallet)").to_bytes(),
+            &person(b"Cow", "CD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826"),
+            &person(b"Bob", "bBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"),
+            &keccak::hash(b"Hello, Bob!").to_bytes(),
+        ])
+        .to_bytes();
+        assert_eq!(mail, hex("c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e"));
+        assert_eq!(
+            eip712_digest(&domain_separator, &mail),
+            hex("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+        );
+    }
+}
@@ -1,18 +1,94 @@
 use solana_program::{
     //msg,
     program_error::ProgramError,
-    program_pack::{IsInitialized, Pack, Sealed},
+    program_pack::{IsInitialized, Sealed},
     pubkey::Pubkey,
 };
 use std::collections::BTreeMap;
 use borsh::{BorshDeserialize, BorshSerialize};
 //yt: program state objects, (de)serializing data arrays of u8
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use crate::{error::PerpError, instruction::{validate_symbol, MAX_SYMBOL_LEN}, signature::SignatureScheme};
 
-const WITHDRAWIDMAP_BYTES: usize = 100;
-const ACCOUNT_BYTES: usize = 1 + 32 + 1 + WITHDRAWIDMAP_BYTES;
-const TOKENMAP_BYTES: usize = 2000;
-const PERPETUAL_BYTES: usize = 1 + 64 + 32 + 32 + 1 + 4 + TOKENMAP_BYTES;
+/// Max account types with a withdraw id window in one user account,
+/// at least the 10 entries of the legacy map so every legacy account migrates
+pub const MAX_ACCOUNT_TYPES: usize = 10;
+/// Withdraw ids tracked by one window from its base
+pub const WITHDRAW_WINDOW: u64 = 256;
+/// Highest withdraw id, the window above it never saturates
+pub const MAX_WITHDRAW_ID: u64 = u64::MAX - WITHDRAW_WINDOW;
+const WITHDRAW_WINDOW_BYTES: usize = WITHDRAW_WINDOW as usize / 8;
+//borsh map: u32 count + (account type, base, bitmap) entries
+const WITHDRAWIDMAP_BYTES: usize = 4 + MAX_ACCOUNT_TYPES * (1 + 8 + WITHDRAW_WINDOW_BYTES);
+/// Max tokens with a daily cap a user withdraws in one day, the next one
+/// fails with PerpError::DailyTokensFull until the day ends
+pub const MAX_DAILY_TOKENS: usize = 8;
+/// Seconds of the day of the per-user daily cap, days start at 00:00 UTC
+pub const DAY_SECONDS: i64 = 24 * 60 * 60;
+//borsh map: u32 count + (account type, symbol, day, amount) entries
+const DAILYMAP_BYTES: usize = 4 + MAX_DAILY_TOKENS * (1 + 4 + MAX_SYMBOL_LEN + 8 + 8);
+/// Size of a user account
+pub const ACCOUNT_BYTES: usize = DISCRIMINATOR_LEN + 1 + 32 + 1 + 4 + WITHDRAWIDMAP_BYTES + 4 + DAILYMAP_BYTES;
+/// Max secp256k1 keys in the withdraw signer set
+pub const MAX_SIGNERS: usize = 5;
+const SIGNERS_BYTES: usize = MAX_SIGNERS * 64;
+/// Max guardians, keys that can only engage pause flags
+pub const MAX_GUARDIANS: usize = 4;
+const GUARDIANS_BYTES: usize = MAX_GUARDIANS * 32;
+/// Size of a perpetual account
+pub const PERPETUAL_BYTES: usize = DISCRIMINATOR_LEN + 1 + 64 + 32 + 32 + 32 + 64 + 8 + 32 + 8
+    + 1 + SIGNERS_BYTES + 1 + 1 + SIGNERS_BYTES + 1 + 8
+    + 1 + 1 + 1 + 1 + GUARDIANS_BYTES + 8;
+//rate limit: window, max amount, window start, current, previous
+const RATE_LIMIT_BYTES: usize = 8 + 8 + 8 + 8 + 8;
+/// Size of a token config account
+pub const TOKEN_CONFIG_BYTES: usize = DISCRIMINATOR_LEN + 1 + 1 + 1 + MAX_SYMBOL_LEN + 32 + 32 + 1
+    + 8 + 1 + RATE_LIMIT_BYTES + 8 + 8 + 8 + 1;
+
+/// Size of the discriminator that starts every state account
+pub const DISCRIMINATOR_LEN: usize = 8;
+/// Discriminator of the perpetual account, a new layout gets a new discriminator.
+/// No discriminator starts with 0 or 1, the is_initialized byte of the legacy layout
+pub const PERPETUAL_DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"perp_v02";
+/// Discriminator of a user account
+pub const ACCOUNT_DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"acct_v02";
+/// Discriminator of a token config account
+pub const TOKEN_CONFIG_DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"tokn_v02";
+const LEGACY_WITHDRAWIDMAP_BYTES: usize = 100;
+/// Size of a legacy user account
+pub const LEGACY_ACCOUNT_BYTES: usize = 1 + 32 + 1 + LEGACY_WITHDRAWIDMAP_BYTES;
+const LEGACY_TOKENMAP_BYTES: usize = 2000;
+/// Size of a legacy perpetual account
+pub const LEGACY_PERPETUAL_BYTES: usize = 1 + 64 + 32 + 32 + 1 + 4 + LEGACY_TOKENMAP_BYTES;
+
+/// Max withdraw validity of a new perpetual account, 1 day
+pub const DEFAULT_MAX_WITHDRAW_VALIDITY: u64 = 24 * 60 * 60;
+/// Pause flag of deposits
+pub const PAUSE_DEPOSIT: u8 = 1 << 0;
+/// Pause flag of withdraws
+pub const PAUSE_WITHDRAW: u8 = 1 << 1;
+/// Every pause flag
+pub const PAUSE_ALL: u8 = PAUSE_DEPOSIT | PAUSE_WITHDRAW;
+
+/// Delay in seconds before a queued secp256k1_pubkey or gateway change takes effect
+pub const CONFIG_TIMELOCK: i64 = 2 * 24 * 60 * 60;
+
+/// Is `data` in the legacy layout, without a discriminator
+pub fn is_legacy(data: &[u8]) -> bool {
+    matches!(data.first(), Some(0) | Some(1))
+}
+
+/// Checks `data` starts with `discriminator`,
+/// PerpError::MigrationRequired for the legacy layout, else PerpError::InvalidDiscriminator
+pub fn check_discriminator(data: &[u8], discriminator: &[u8; DISCRIMINATOR_LEN]) -> Result<(), ProgramError> {
+    if data.get(..DISCRIMINATOR_LEN) == Some(&discriminator[..]) {
+        return Ok(());
+    }
+    if is_legacy(data) {
+        return Err(PerpError::MigrationRequired.into());
+    }
+    Err(PerpError::InvalidDiscriminator.into())
+}
 
 //can be: pub const fn from_le_bytes(bytes: [u8; 4]) -> u32
 fn count_from_le(array: &[u8]) -> usize {
@@ -22,23 +98,533 @@ fn count_from_le(array: &[u8]) -> usize {
         | (array[3] as usize) << 24
 }
 
+//borsh map of len bytes at the start of src
+fn unpack_map<K, V>(src: &[u8], len: usize) -> Result<BTreeMap<K, V>, ProgramError>
+where
+    BTreeMap<K, V>: BorshDeserialize,
+{
+    let data = src.get(..len).ok_or(ProgramError::InvalidAccountData)?;
+    BTreeMap::<K, V>::try_from_slice(data).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+//borsh map into the start of dst, zero filled, return the le byte count
+fn pack_map<K, V>(map: &BTreeMap<K, V>, dst: &mut [u8]) -> Result<[u8; 4], ProgramError>
+where
+    BTreeMap<K, V>: BorshSerialize,
+{
+    let data_ser = map.try_to_vec().map_err(|_| ProgramError::InvalidAccountData)?;
+    if data_ser.len() > dst.len() {
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    dst.fill(0);
+    dst[..data_ser.len()].copy_from_slice(&data_ser);
+    Ok((data_ser.len() as u32).to_le_bytes())
+}
+
+//count + fixed region of 64 bytes keys
+fn unpack_signers(count: u8, src: &[u8; SIGNERS_BYTES]) -> Result<Vec<[u8; 64]>, ProgramError> {
+    let count = count as usize;
+    if count > MAX_SIGNERS {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let signers = src
+        .chunks_exact(64)
+        .take(count)
+        .map(|key| {
+            let mut signer = [0u8; 64];
+            signer.copy_from_slice(key);
+            signer
+        })
+        .collect();
+    Ok(signers)
+}
+
+fn pack_signers(signers: &[[u8; 64]], count_dst: &mut [u8; 1], dst: &mut [u8; SIGNERS_BYTES]) -> Result<(), ProgramError> {
+    if signers.len() > MAX_SIGNERS {
+        return Err(PerpError::InvalidSignerSet.into());
+    }
+    dst.fill(0);
+    let mut count = 0;
+    for (key_dst, signer) in dst.chunks_exact_mut(64).zip(signers) {
+        key_dst.copy_from_slice(signer);
+        count += 1;
+    }
+    count_dst[0] = count;
+    Ok(())
+}
+
+//count + fixed region of pubkeys
+fn unpack_guardians(count: u8, src: &[u8; GUARDIANS_BYTES]) -> Result<Vec<Pubkey>, ProgramError> {
+    let count = count as usize;
+    if count > MAX_GUARDIANS {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let guardians = src
+        .chunks_exact(32)
+        .take(count)
+        .map(|key| {
+            let mut guardian = [0u8; 32];
+            guardian.copy_from_slice(key);
+            Pubkey::new_from_array(guardian)
+        })
+        .collect();
+    Ok(guardians)
+}
+
+fn pack_guardians(guardians: &[Pubkey], count_dst: &mut [u8; 1], dst: &mut [u8; GUARDIANS_BYTES]) -> Result<(), ProgramError> {
+    if guardians.len() > MAX_GUARDIANS {
+        return Err(ProgramError::InvalidArgument);
+    }
+    dst.fill(0);
+    let mut count = 0;
+    for (key_dst, guardian) in dst.chunks_exact_mut(32).zip(guardians) {
+        key_dst.copy_from_slice(guardian.as_ref());
+        count += 1;
+    }
+    count_dst[0] = count;
+    Ok(())
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Hash, Ord, Eq, PartialEq, PartialOrd)]
 pub struct TypeSymbol {
     pub account_type: u8, //from 0
     pub symbol: String,   //USDC, SOL, ...
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, Copy, Clone)]
-pub struct MintProgram {
-    pub mint: Pubkey, 
+/// Registry entry of one supported token, the pda of
+/// [TOKEN_SEED, account type, symbol], see crate::find_token_address
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenConfig {
+    pub is_initialized: bool,
+    pub account_type: u8,
+    pub symbol: String,
+    pub mint: Pubkey,
     pub program_token_account: Pubkey,
+    //decimals of the mint
+    pub decimals: u8,
+    //withdraw amount from which signer_threshold signatures of signers are required, 0 is never
+    pub multisig_amount: u64,
+    //PAUSE_DEPOSIT, PAUSE_WITHDRAW of this token
+    pub pause_flags: u8,
+    //withdraw cap over a rolling window
+    pub rate_limit: RateLimit,
+    //max amount of one withdraw, 0 is no limit
+    pub max_withdraw_amount: u64,
+    //max amount one user withdraws in a day, 0 is no limit
+    pub user_daily_cap: u64,
+    //withdraw amount from which the admin or gateway must co-sign, 0 is never
+    pub cosign_amount: u64,
+    pub bump_seed: u8,
+}
+
+impl TokenConfig {
+    /// Config of a new token, no limits, pause or multisig,
+    /// PerpError::EmptySymbol, or PerpError::SymbolTooLong over MAX_SYMBOL_LEN, the pda seed must fit the symbol region
+    pub fn new(account_type: u8, symbol: String, bump_seed: u8) -> Result<Self, PerpError> {
+        validate_symbol(&symbol)?;
+        Ok(TokenConfig {
+            is_initialized: true,
+            account_type,
+            symbol,
+            mint: Pubkey::default(),
+            program_token_account: Pubkey::default(),
+            decimals: 0,
+            multisig_amount: 0,
+            pause_flags: 0,
+            rate_limit: RateLimit::default(),
+            max_withdraw_amount: 0,
+            user_daily_cap: 0,
+            cosign_amount: 0,
+            bump_seed,
+        })
+    }
+
+    /// The token of this config
+    pub fn token(&self) -> TypeSymbol {
+        TypeSymbol {
+            account_type: self.account_type,
+            symbol: self.symbol.clone(),
+        }
+    }
+}
+
+impl Sealed for TokenConfig {}
+
+impl IsInitialized for TokenConfig {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+//not Pack, packing fails on a symbol over its region instead of truncating it
+impl TokenConfig {
+    /// Unpacks an initialized token config account, checks the discriminator
+    pub fn load(src: &[u8]) -> Result<Self, ProgramError> {
+        check_discriminator(src, &TOKEN_CONFIG_DISCRIMINATOR)?;
+        if src.len() < TOKEN_CONFIG_BYTES {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, TOKEN_CONFIG_BYTES];
+        let (
+            _discriminator,
+            is_initialized,
+            account_type,
+            symbol_len,
+            symbol,
+            mint,
+            program_token_account,
+            decimals,
+            multisig_amount,
+            pause_flags,
+            window,
+            max_amount,
+            window_start,
+            current,
+            previous,
+            max_withdraw_amount,
+            user_daily_cap,
+            cosign_amount,
+            bump_seed,
+        ) = array_refs![src, DISCRIMINATOR_LEN, 1, 1, 1, MAX_SYMBOL_LEN, 32, 32, 1,
+            8, 1, 8, 8, 8, 8, 8, 8, 8, 8, 1];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        if !is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        let symbol_len = symbol_len[0] as usize;
+        if symbol_len > MAX_SYMBOL_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let symbol = std::str::from_utf8(&symbol[..symbol_len])
+            .map_err(|_| ProgramError::InvalidAccountData)?
+            .to_string();
+        Ok(TokenConfig {
+            is_initialized,
+            account_type: account_type[0],
+            symbol,
+            mint: Pubkey::new_from_array(*mint),
+            program_token_account: Pubkey::new_from_array(*program_token_account),
+            decimals: decimals[0],
+            multisig_amount: u64::from_le_bytes(*multisig_amount),
+            pause_flags: pause_flags[0],
+            rate_limit: RateLimit {
+                window: u64::from_le_bytes(*window),
+                max_amount: u64::from_le_bytes(*max_amount),
+                window_start: i64::from_le_bytes(*window_start),
+                current: u64::from_le_bytes(*current),
+                previous: u64::from_le_bytes(*previous),
+            },
+            max_withdraw_amount: u64::from_le_bytes(*max_withdraw_amount),
+            user_daily_cap: u64::from_le_bytes(*user_daily_cap),
+            cosign_amount: u64::from_le_bytes(*cosign_amount),
+            bump_seed: bump_seed[0],
+        })
+    }
+
+    /// Packs into the first TOKEN_CONFIG_BYTES of the account,
+    /// PerpError::EmptySymbol or PerpError::SymbolTooLong if the symbol is over its region
+    pub fn store(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < TOKEN_CONFIG_BYTES {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let dst = array_mut_ref![dst, 0, TOKEN_CONFIG_BYTES];
+        let (
+            discriminator_dst,
+            is_initialized_dst,
+            account_type_dst,
+            symbol_len_dst,
+            symbol_dst,
+            mint_dst,
+            program_token_account_dst,
+            decimals_dst,
+            multisig_amount_dst,
+            pause_flags_dst,
+            window_dst,
+            max_amount_dst,
+            window_start_dst,
+            current_dst,
+            previous_dst,
+            max_withdraw_amount_dst,
+            user_daily_cap_dst,
+            cosign_amount_dst,
+            bump_seed_dst,
+        ) = mut_array_refs![dst, DISCRIMINATOR_LEN, 1, 1, 1, MAX_SYMBOL_LEN, 32, 32, 1,
+            8, 1, 8, 8, 8, 8, 8, 8, 8, 8, 1];
+        let TokenConfig {
+            is_initialized,
+            account_type,
+            symbol,
+            mint,
+            program_token_account,
+            decimals,
+            multisig_amount,
+            pause_flags,
+            rate_limit,
+            max_withdraw_amount,
+            user_daily_cap,
+            cosign_amount,
+            bump_seed,
+        } = self;
+        //a truncated symbol would no longer match the pda seeds
+        validate_symbol(symbol)?;
+
+        *discriminator_dst = TOKEN_CONFIG_DISCRIMINATOR;
+        is_initialized_dst[0] = *is_initialized as u8;
+        account_type_dst[0] = *account_type;
+        symbol_len_dst[0] = symbol.len() as u8;
+        symbol_dst.fill(0);
+        symbol_dst[..symbol.len()].copy_from_slice(symbol.as_bytes());
+        mint_dst.copy_from_slice(mint.as_ref());
+        program_token_account_dst.copy_from_slice(program_token_account.as_ref());
+        decimals_dst[0] = *decimals;
+        *multisig_amount_dst = multisig_amount.to_le_bytes();
+        pause_flags_dst[0] = *pause_flags;
+        *window_dst = rate_limit.window.to_le_bytes();
+        *max_amount_dst = rate_limit.max_amount.to_le_bytes();
+        *window_start_dst = rate_limit.window_start.to_le_bytes();
+        *current_dst = rate_limit.current.to_le_bytes();
+        *previous_dst = rate_limit.previous.to_le_bytes();
+        *max_withdraw_amount_dst = max_withdraw_amount.to_le_bytes();
+        *user_daily_cap_dst = user_daily_cap.to_le_bytes();
+        *cosign_amount_dst = cosign_amount.to_le_bytes();
+        bump_seed_dst[0] = *bump_seed;
+        Ok(())
+    }
+}
+
+/// Withdraw cap of one token over a rolling window, the previous fixed window
+/// counts for the part that still overlaps the rolling one
+#[derive(BorshSerialize, BorshDeserialize, Debug, Copy, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    //window in seconds, 0 is no limit
+    pub window: u64,
+    //max amount withdrawn over any window
+    pub max_amount: u64,
+    //start of the current fixed window
+    pub window_start: i64,
+    //withdrawn in the current fixed window
+    pub current: u64,
+    //withdrawn in the previous fixed window
+    pub previous: u64,
+}
+
+impl RateLimit {
+    //move the fixed windows up to now
+    fn roll(&mut self, now: i64) {
+        let window = self.window as i128;
+        let elapsed = now as i128 - self.window_start as i128;
+        if elapsed < window {
+            return;
+        }
+        if elapsed < 2 * window {
+            self.previous = self.current;
+            self.window_start = (self.window_start as i128 + window) as i64;
+        } else {
+            self.previous = 0;
+            self.window_start = (now as i128 - elapsed % window) as i64;
+        }
+        self.current = 0;
+    }
+
+    //amount counted against max_amount at now, the windows must be rolled
+    fn used(&self, now: i64) -> u128 {
+        let window = self.window as i128;
+        let elapsed = (now as i128 - self.window_start as i128).clamp(0, window);
+        let previous = self.previous as i128 * (window - elapsed) / window;
+        previous as u128 + self.current as u128
+    }
+
+    /// Counts a withdraw of `amount` at `now`, fails when over max_amount
+    pub fn consume(&mut self, amount: u64, now: i64) -> Result<(), PerpError> {
+        if self.window == 0 {
+            return Ok(());
+        }
+        self.roll(now);
+        if self.used(now) + amount as u128 > self.max_amount as u128 {
+            return Err(PerpError::RateLimited);
+        }
+        self.current = self.current.saturating_add(amount);
+        Ok(())
+    }
+}
+
+/// Replay protection of one account type: ids below base are dead,
+/// ids in [base, base + WITHDRAW_WINDOW) are used once in any order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WithdrawWindow {
+    //lowest unused id
+    pub base: u64,
+    //bit i, lsb first, is id base + i used
+    pub bitmap: [u8; WITHDRAW_WINDOW_BYTES],
+}
+
+impl WithdrawWindow {
+    /// Window of a new account type, withdraw ids start from 1
+    pub fn new() -> Self {
+        WithdrawWindow {
+            base: 1,
+            bitmap: [0; WITHDRAW_WINDOW_BYTES],
+        }
+    }
+
+    /// Is `withdrawid` dead or used
+    pub fn is_used(&self, withdrawid: u64) -> bool {
+        if withdrawid < self.base {
+            return true;
+        }
+        let offset = withdrawid - self.base;
+        offset < WITHDRAW_WINDOW
+            && self.bitmap[(offset / 8) as usize] & (1 << (offset % 8)) != 0
+    }
+
+    /// Marks `withdrawid` used, an id above the window slides it up
+    /// and kills the ids falling below
+    pub fn use_id(&mut self, withdrawid: u64) -> Result<(), PerpError> {
+        if withdrawid > MAX_WITHDRAW_ID || self.is_used(withdrawid) {
+            return Err(PerpError::WithdrawIdFail);
+        }
+        let top = self.base + (WITHDRAW_WINDOW - 1);
+        if withdrawid > top {
+            self.slide(withdrawid - top);
+        }
+        self.mark(withdrawid);
+        self.advance();
+        Ok(())
+    }
+
+    /// Marks an unused `withdrawid` inside the window used, never moves base,
+    /// so cancels alone burn at most the WITHDRAW_WINDOW ids of the window
+    pub fn cancel_id(&mut self, withdrawid: u64) -> Result<(), PerpError> {
+        //dead ids are used, so the offset doesn't underflow
+        if withdrawid > MAX_WITHDRAW_ID || self.is_used(withdrawid) || withdrawid - self.base >= WITHDRAW_WINDOW {
+            return Err(PerpError::WithdrawIdFail);
+        }
+        self.mark(withdrawid);
+        Ok(())
+    }
+
+    //sets the bit of an id inside the window
+    fn mark(&mut self, withdrawid: u64) {
+        let offset = withdrawid - self.base;
+        self.bitmap[(offset / 8) as usize] |= 1 << (offset % 8);
+    }
+
+    //base to the lowest unused id, over used and cancelled ids
+    fn advance(&mut self) {
+        let mut used = 0;
+        for byte in self.bitmap.iter() {
+            used += byte.trailing_ones() as u64;
+            if *byte != u8::MAX {
+                break;
+            }
+        }
+        self.slide(used);
+    }
+
+    //moves base up by n, bit i becomes bit i - n
+    fn slide(&mut self, n: u64) {
+        if n == 0 {
+            return;
+        }
+        let mut bitmap = [0u8; WITHDRAW_WINDOW_BYTES];
+        if n < WITHDRAW_WINDOW {
+            let bytes = (n / 8) as usize;
+            let bits = (n % 8) as u32;
+            for (i, byte) in bitmap.iter_mut().enumerate() {
+                let lo = self.bitmap.get(i + bytes).copied().unwrap_or(0);
+                let hi = self.bitmap.get(i + bytes + 1).copied().unwrap_or(0);
+                *byte = if bits == 0 { lo } else { (lo >> bits) | (hi << (8 - bits)) };
+            }
+        }
+        self.bitmap = bitmap;
+        self.base = self.base.saturating_add(n);
+    }
+}
+
+impl Default for WithdrawWindow {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct Account {
     pub is_initialized: bool,
     pub user: Pubkey,
-    pub withdraw_id: BTreeMap<u8, u64>,
+    //canonical bump of the account pda, checked once at init
+    pub bump_seed: u8,
+    pub withdraw_id: BTreeMap<u8, WithdrawWindow>,
+    //withdrawn today of tokens with a user daily cap
+    pub daily_withdrawn: BTreeMap<TypeSymbol, DailyWithdrawn>,
+}
+
+/// Amount withdrawn by a user in one day
+#[derive(BorshSerialize, BorshDeserialize, Debug, Copy, Clone, Default, PartialEq)]
+pub struct DailyWithdrawn {
+    //unix timestamp / DAY_SECONDS
+    pub day: i64,
+    pub amount: u64,
+}
+
+impl Account {
+    /// Initialized account of `user` at the pda of `bump_seed`, no withdraw used
+    pub fn new(user: Pubkey, bump_seed: u8) -> Self {
+        Account {
+            is_initialized: true,
+            user,
+            bump_seed,
+            withdraw_id: BTreeMap::new(),
+            daily_withdrawn: BTreeMap::new(),
+        }
+    }
+
+    /// Marks `withdrawid` of `account_type` used, fails on a replayed or dead id
+    pub fn use_withdraw_id(&mut self, account_type: u8, withdrawid: u64) -> Result<(), PerpError> {
+        //a window per account type, the map region holds MAX_ACCOUNT_TYPES
+        if !self.withdraw_id.contains_key(&account_type) && self.withdraw_id.len() >= MAX_ACCOUNT_TYPES {
+            return Err(PerpError::WithdrawIdMapFull);
+        }
+        self.withdraw_id
+            .entry(account_type)
+            .or_default()
+            .use_id(withdrawid)
+    }
+
+    /// Burns an unused `withdrawid` inside the window of `account_type`, never slides the window.
+    /// Only a withdraw creates a window, a cancel of an account type without one fails
+    pub fn cancel_withdraw_id(&mut self, account_type: u8, withdrawid: u64) -> Result<(), PerpError> {
+        self.withdraw_id
+            .get_mut(&account_type)
+            .ok_or(PerpError::WithdrawIdFail)?
+            .cancel_id(withdrawid)
+    }
+
+    /// Counts `amount` of `token` withdrawn at `now`, fails over `daily_cap`
+    /// or for a new token once MAX_DAILY_TOKENS tokens are withdrawn today
+    pub fn use_daily_cap(&mut self, token: &TypeSymbol, amount: u64, daily_cap: u64, now: i64) -> Result<(), PerpError> {
+        let day = now.div_euclid(DAY_SECONDS);
+        //entries of past days are free to reuse
+        if !self.daily_withdrawn.contains_key(token) && self.daily_withdrawn.len() >= MAX_DAILY_TOKENS {
+            self.daily_withdrawn.retain(|_, withdrawn| withdrawn.day == day);
+            if self.daily_withdrawn.len() >= MAX_DAILY_TOKENS {
+                return Err(PerpError::DailyTokensFull);
+            }
+        }
+        let withdrawn = self.daily_withdrawn.entry(token.clone()).or_default();
+        if withdrawn.day != day {
+            *withdrawn = DailyWithdrawn { day, amount: 0 };
+        }
+        let total = withdrawn.amount.checked_add(amount).ok_or(PerpError::DailyCapExceeded)?;
+        if total > daily_cap {
+            return Err(PerpError::DailyCapExceeded);
+        }
+        withdrawn.amount = total;
+        Ok(())
+    }
 }
 
 impl Sealed for Account {} //trait in program_pack size
@@ -49,58 +635,101 @@ impl IsInitialized for Account {
     }
 }
 
-impl Pack for Account {
-    const LEN: usize = ACCOUNT_BYTES;
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, Account::LEN]; //get references to sections of a slice
+//not Pack, packing fails on a map over its region instead of panicking
+impl Account {
+    /// Unpacks an initialized user account, checks the discriminator
+    pub fn load(src: &[u8]) -> Result<Self, ProgramError> {
+        let account = Self::load_unchecked(src)?;
+        if !account.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(account)
+    }
+
+    /// Unpacks without the initialized check
+    pub fn load_unchecked(src: &[u8]) -> Result<Self, ProgramError> {
+        check_discriminator(src, &ACCOUNT_DISCRIMINATOR)?;
+        if src.len() < ACCOUNT_BYTES {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, ACCOUNT_BYTES]; //get references to sections of a slice
         let (
+            _discriminator,
             is_initialized,
             user,
+            bump_seed,
             withdrawid_len,
             withdraw_id,
-        ) = array_refs![src, 1, 32, 1, WITHDRAWIDMAP_BYTES];
+            daily_len,
+            daily_withdrawn,
+        ) = array_refs![src, DISCRIMINATOR_LEN, 1, 32, 1, 4, WITHDRAWIDMAP_BYTES, 4, DAILYMAP_BYTES];
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
             _ => return Err(ProgramError::InvalidAccountData),
         };
         let user = Pubkey::new_from_array(*user);
-        //let withdraw_id = u16::from_le_bytes(*withdraw_id);
         //withdrawid map
-        let withdrawid_len = u8::from_le_bytes(*withdrawid_len) as usize; //count_from_le(position_len);
+        let withdrawid_len = count_from_le(withdrawid_len);
         let withdraw_id = 
-            if withdrawid_len == 0 {BTreeMap::<u8, u64>::new()}
-            else                   {BTreeMap::<u8, u64>::try_from_slice(&withdraw_id[0..withdrawid_len]).unwrap()};
+            if withdrawid_len == 0 {BTreeMap::<u8, WithdrawWindow>::new()}
+            else                   {unpack_map(withdraw_id, withdrawid_len)?};
+        if withdraw_id.len() > MAX_ACCOUNT_TYPES {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        //daily withdrawn map
+        let daily_len = count_from_le(daily_len);
+        let daily_withdrawn =
+            if daily_len == 0 {BTreeMap::<TypeSymbol, DailyWithdrawn>::new()}
+            else              {unpack_map(daily_withdrawn, daily_len)?};
         //return
         Ok(Account {
             is_initialized,
             user,
+            bump_seed: bump_seed[0],
             withdraw_id,
+            daily_withdrawn,
         })
     }
 
-    fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, Account::LEN];
+    /// Packs into the first ACCOUNT_BYTES of the account,
+    /// ProgramError::AccountDataTooSmall if a map is over its region
+    pub fn store(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < ACCOUNT_BYTES {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let dst = array_mut_ref![dst, 0, ACCOUNT_BYTES];
         let (
+            discriminator_dst,
             is_initialized_dst,
             user_dst,
+            bump_seed_dst,
             withdrawid_len,
             withdraw_id_dst,
-        ) = mut_array_refs![dst, 1, 32, 1, WITHDRAWIDMAP_BYTES];
+            daily_len,
+            daily_withdrawn_dst,
+        ) = mut_array_refs![dst, DISCRIMINATOR_LEN, 1, 32, 1, 4, WITHDRAWIDMAP_BYTES, 4, DAILYMAP_BYTES];
 
         let Account {
             is_initialized,
             user,
+            bump_seed,
             withdraw_id,
+            daily_withdrawn,
         } = self;
+        if withdraw_id.len() > MAX_ACCOUNT_TYPES {
+            return Err(PerpError::WithdrawIdMapFull.into());
+        }
 
+        *discriminator_dst = ACCOUNT_DISCRIMINATOR;
         is_initialized_dst[0] = *is_initialized as u8;
         user_dst.copy_from_slice(user.as_ref());
+        bump_seed_dst[0] = *bump_seed;
         //withdrawid map
-        let data_ser = withdraw_id.try_to_vec().unwrap();
-        //msg!("withdraw_id bytes len:{}",data_ser.len());
-        *withdrawid_len = (data_ser.len() as u8).to_le_bytes();
-        withdraw_id_dst[..data_ser.len()].copy_from_slice(&data_ser);
+        *withdrawid_len = pack_map(withdraw_id, withdraw_id_dst)?;
+        //daily withdrawn map
+        *daily_len = pack_map(daily_withdrawn, daily_withdrawn_dst)?;
+        Ok(())
     }
 }
 
@@ -109,15 +738,138 @@ pub struct Perpetual {
     //del or add more checks
     pub is_initialized: bool,
     pub secp256k1_pubkey: [u8; 64],
+    //signs BatchWithdraw and RelayedWithdraw
     pub gateway: Pubkey,
     pub admin: Pubkey,
+    //proposed by admin, becomes admin after its signed AcceptAdmin, default() is none
+    pub pending_admin: Pubkey,
+    //queued signer key, replaces secp256k1_pubkey at secp256k1_pubkey_effective_at, 0 is none
+    pub pending_secp256k1_pubkey: [u8; 64],
+    pub secp256k1_pubkey_effective_at: i64,
+    //queued gateway, replaces gateway at gateway_effective_at, 0 is none
+    pub pending_gateway: Pubkey,
+    pub gateway_effective_at: i64,
+    //M-of-N withdraw signer set, at most MAX_SIGNERS keys, empty is no multisig
+    pub signers: Vec<[u8; 64]>,
+    pub signer_threshold: u8,
+    //queued signer set, replaces signers at signers_effective_at, 0 is none
+    pub pending_signers: Vec<[u8; 64]>,
+    pub pending_signer_threshold: u8,
+    pub signers_effective_at: i64,
+    //how withdraw messages are hashed for secp256k1_recover
+    pub signature_scheme: SignatureScheme,
+    //global PAUSE_DEPOSIT, PAUSE_WITHDRAW, on top of the token flags
+    pub pause_flags: u8,
+    //on-call keys that can only engage pause flags, at most MAX_GUARDIANS
+    pub guardians: Vec<Pubkey>,
+    //max seconds from now to a withdraw timestamp, 0 is no limit
+    pub max_withdraw_validity: u64,
     //pub program_token_account: Pubkey,
     //pub pda: Pubkey,
     pub bump_seed: u8,
-    pub token_map: BTreeMap<TypeSymbol, MintProgram>,
     //pub user_map: BTreeMap<Pubkey, bool>,
 }
 
+/// Signer roles of the perpetual account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// config changes and the token configs
+    Admin,
+    /// BatchWithdraw, RelayedWithdraw, CancelWithdrawId, engaging pause flags,
+    /// rotated by the admin with a queued ConfigChange::Gateway
+    Gateway,
+    /// engaging pause flags only, set by the admin with SetGuardians
+    Guardian,
+}
+
+impl Perpetual {
+    /// Initialized perpetual account, no pending changes, signer set, guardians or pause
+    pub fn new(secp256k1_pubkey: [u8; 64], gateway: Pubkey, admin: Pubkey, bump_seed: u8) -> Self {
+        Perpetual {
+            is_initialized: true,
+            secp256k1_pubkey,
+            gateway,
+            admin,
+            pending_admin: Pubkey::default(),
+            pending_secp256k1_pubkey: [0; 64],
+            secp256k1_pubkey_effective_at: 0,
+            pending_gateway: Pubkey::default(),
+            gateway_effective_at: 0,
+            signers: Vec::new(),
+            signer_threshold: 0,
+            pending_signers: Vec::new(),
+            pending_signer_threshold: 0,
+            signers_effective_at: 0,
+            signature_scheme: SignatureScheme::Keccak,
+            pause_flags: 0,
+            guardians: Vec::new(),
+            max_withdraw_validity: DEFAULT_MAX_WITHDRAW_VALIDITY,
+            bump_seed,
+        }
+    }
+
+    /// Does `key` hold `role` at `now`
+    pub fn has_role(&self, key: &Pubkey, role: Role, now: i64) -> bool {
+        match role {
+            Role::Admin => self.admin == *key,
+            Role::Gateway => self.active_gateway(now) == *key,
+            Role::Guardian => self.guardians.contains(key),
+        }
+    }
+
+    /// Is `flag` set globally or in the `token_flags` of a token config
+    pub fn is_paused(&self, token_flags: u8, flag: u8) -> bool {
+        (self.pause_flags | token_flags) & flag != 0
+    }
+
+    /// Signer key used at `now`, a queued key counts once its delay elapsed
+    pub fn active_secp256k1_pubkey(&self, now: i64) -> [u8; 64] {
+        if self.secp256k1_pubkey_effective_at != 0 && now >= self.secp256k1_pubkey_effective_at {
+            self.pending_secp256k1_pubkey
+        } else {
+            self.secp256k1_pubkey
+        }
+    }
+
+    /// Gateway used at `now`, a queued gateway counts once its delay elapsed
+    pub fn active_gateway(&self, now: i64) -> Pubkey {
+        if self.gateway_effective_at != 0 && now >= self.gateway_effective_at {
+            self.pending_gateway
+        } else {
+            self.gateway
+        }
+    }
+
+    /// Signer set and threshold used at `now`, a queued set counts once its delay elapsed
+    pub fn active_signers(&self, now: i64) -> (&[[u8; 64]], u8) {
+        if self.signers_effective_at != 0 && now >= self.signers_effective_at {
+            (&self.pending_signers, self.pending_signer_threshold)
+        } else {
+            (&self.signers, self.signer_threshold)
+        }
+    }
+
+    /// Move the queued changes whose delay elapsed into the active config
+    pub fn apply_config_changes(&mut self, now: i64) {
+        self.secp256k1_pubkey = self.active_secp256k1_pubkey(now);
+        self.gateway = self.active_gateway(now);
+        if self.secp256k1_pubkey_effective_at != 0 && now >= self.secp256k1_pubkey_effective_at {
+            self.pending_secp256k1_pubkey = [0; 64];
+            self.secp256k1_pubkey_effective_at = 0;
+        }
+        if self.gateway_effective_at != 0 && now >= self.gateway_effective_at {
+            self.pending_gateway = Pubkey::default();
+            self.gateway_effective_at = 0;
+        }
+        if self.signers_effective_at != 0 && now >= self.signers_effective_at {
+            self.signers = std::mem::take(&mut self.pending_signers);
+            self.signer_threshold = self.pending_signer_threshold;
+            self.pending_signer_threshold = 0;
+            self.signers_effective_at = 0;
+        }
+    }
+}
+
 impl Sealed for Perpetual {} //trait in program_pack
 
 impl IsInitialized for Perpetual {
@@ -126,19 +878,52 @@ impl IsInitialized for Perpetual {
     }
 }
 
-impl Pack for Perpetual {
-    const LEN: usize = PERPETUAL_BYTES;
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, Perpetual::LEN]; //get references to sections of a slice
+//not Pack, the legacy layout of the same address is larger than PERPETUAL_BYTES
+impl Perpetual {
+    /// Unpacks an initialized perpetual account, checks the discriminator,
+    /// PerpError::MigrationRequired for the legacy layout
+    pub fn load(src: &[u8]) -> Result<Self, ProgramError> {
+        let perpetual = Self::load_unchecked(src)?;
+        if !perpetual.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(perpetual)
+    }
+
+    /// Unpacks without the initialized check
+    pub fn load_unchecked(src: &[u8]) -> Result<Self, ProgramError> {
+        check_discriminator(src, &PERPETUAL_DISCRIMINATOR)?;
+        if src.len() < PERPETUAL_BYTES {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, PERPETUAL_BYTES]; //get references to sections of a slice
         let (
+            _discriminator,
             is_initialized,
             secp256k1_pubkey,
             gateway,
             admin,
+            pending_admin,
+            pending_secp256k1_pubkey,
+            secp256k1_pubkey_effective_at,
+            pending_gateway,
+            gateway_effective_at,
+            signers_len,
+            signers,
+            signer_threshold,
+            pending_signers_len,
+            pending_signers,
+            pending_signer_threshold,
+            signers_effective_at,
+            signature_scheme,
+            pause_flags,
             bump_seed,
-            token_map_len,
-            token_map,
-        ) = array_refs![src, 1, 64, 32, 32, 1, 4, TOKENMAP_BYTES];
+            guardians_len,
+            guardians,
+            max_withdraw_validity,
+        ) = array_refs![src, DISCRIMINATOR_LEN, 1, 64, 32, 32, 32, 64, 8, 32, 8,
+            1, SIGNERS_BYTES, 1, 1, SIGNERS_BYTES, 1, 8,
+            1, 1, 1, 1, GUARDIANS_BYTES, 8];
         //every data from &[u8; _]
         let is_initialized = match is_initialized {
             [0] => false,
@@ -148,54 +933,711 @@ impl Pack for Perpetual {
         let secp256k1_pubkey = *secp256k1_pubkey;
         let gateway = Pubkey::new_from_array(*gateway);
         let admin = Pubkey::new_from_array(*admin);
+        let pending_admin = Pubkey::new_from_array(*pending_admin);
+        let pending_secp256k1_pubkey = *pending_secp256k1_pubkey;
+        let secp256k1_pubkey_effective_at = i64::from_le_bytes(*secp256k1_pubkey_effective_at);
+        let pending_gateway = Pubkey::new_from_array(*pending_gateway);
+        let gateway_effective_at = i64::from_le_bytes(*gateway_effective_at);
+        let signers = unpack_signers(signers_len[0], signers)?;
+        let signer_threshold = signer_threshold[0];
+        let pending_signers = unpack_signers(pending_signers_len[0], pending_signers)?;
+        let pending_signer_threshold = pending_signer_threshold[0];
+        let signers_effective_at = i64::from_le_bytes(*signers_effective_at);
+        let signature_scheme = SignatureScheme::from_u8(signature_scheme[0])
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let pause_flags = pause_flags[0];
         let bump_seed = bump_seed[0];
-        //token map
-        let token_map_len = count_from_le(token_map_len);
-        let token_map = 
-            if token_map_len == 0 {BTreeMap::<TypeSymbol, MintProgram>::new()}
-            else                  {BTreeMap::<TypeSymbol, MintProgram>::try_from_slice(&token_map[0..token_map_len]).unwrap()};
+        let guardians = unpack_guardians(guardians_len[0], guardians)?;
+        let max_withdraw_validity = u64::from_le_bytes(*max_withdraw_validity);
         //return
         Ok(Perpetual {
             is_initialized,
             secp256k1_pubkey,
             gateway,
             admin,
+            pending_admin,
+            pending_secp256k1_pubkey,
+            secp256k1_pubkey_effective_at,
+            pending_gateway,
+            gateway_effective_at,
+            signers,
+            signer_threshold,
+            pending_signers,
+            pending_signer_threshold,
+            signers_effective_at,
+            signature_scheme,
+            pause_flags,
+            guardians,
+            max_withdraw_validity,
             bump_seed,
-            token_map,
         })
     }
 
-    fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, Perpetual::LEN];
+    /// Packs into the first PERPETUAL_BYTES of the account
+    pub fn store(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < PERPETUAL_BYTES {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let dst = array_mut_ref![dst, 0, PERPETUAL_BYTES];
         let (
+            discriminator_dst,
             is_initialized_dst,
             secp256k1_pubkey_dst,
             gateway_dst,
             admin_dst,
+            pending_admin_dst,
+            pending_secp256k1_pubkey_dst,
+            secp256k1_pubkey_effective_at_dst,
+            pending_gateway_dst,
+            gateway_effective_at_dst,
+            signers_len_dst,
+            signers_dst,
+            signer_threshold_dst,
+            pending_signers_len_dst,
+            pending_signers_dst,
+            pending_signer_threshold_dst,
+            signers_effective_at_dst,
+            signature_scheme_dst,
+            pause_flags_dst,
             bump_seed_dst,
-            token_map_len,
-            token_map_dst,
-        ) = mut_array_refs![dst, 1, 64, 32, 32, 1, 4, TOKENMAP_BYTES];
+            guardians_len_dst,
+            guardians_dst,
+            max_withdraw_validity_dst,
+        ) = mut_array_refs![dst, DISCRIMINATOR_LEN, 1, 64, 32, 32, 32, 64, 8, 32, 8,
+            1, SIGNERS_BYTES, 1, 1, SIGNERS_BYTES, 1, 8,
+            1, 1, 1, 1, GUARDIANS_BYTES, 8];
 
         let Perpetual {
             is_initialized,
             secp256k1_pubkey,
             gateway,
             admin,
+            pending_admin,
+            pending_secp256k1_pubkey,
+            secp256k1_pubkey_effective_at,
+            pending_gateway,
+            gateway_effective_at,
+            signers,
+            signer_threshold,
+            pending_signers,
+            pending_signer_threshold,
+            signers_effective_at,
+            signature_scheme,
+            pause_flags,
+            guardians,
+            max_withdraw_validity,
             bump_seed,
-            token_map,
         } = self;
 
+        *discriminator_dst = PERPETUAL_DISCRIMINATOR;
         is_initialized_dst[0] = *is_initialized as u8;
         //*signer_eth_pubkey_dst = *signer_eth_pubkey;
         secp256k1_pubkey_dst.copy_from_slice(secp256k1_pubkey);
         gateway_dst.copy_from_slice(gateway.as_ref());
         admin_dst.copy_from_slice(admin.as_ref());
+        pending_admin_dst.copy_from_slice(pending_admin.as_ref());
+        pending_secp256k1_pubkey_dst.copy_from_slice(pending_secp256k1_pubkey);
+        *secp256k1_pubkey_effective_at_dst = secp256k1_pubkey_effective_at.to_le_bytes();
+        pending_gateway_dst.copy_from_slice(pending_gateway.as_ref());
+        *gateway_effective_at_dst = gateway_effective_at.to_le_bytes();
+        pack_signers(signers, signers_len_dst, signers_dst)?;
+        signer_threshold_dst[0] = *signer_threshold;
+        pack_signers(pending_signers, pending_signers_len_dst, pending_signers_dst)?;
+        pending_signer_threshold_dst[0] = *pending_signer_threshold;
+        *signers_effective_at_dst = signers_effective_at.to_le_bytes();
+        signature_scheme_dst[0] = *signature_scheme as u8;
+        pause_flags_dst[0] = *pause_flags;
         bump_seed_dst[0] = *bump_seed;
-        //token_map
-        let data_ser = token_map.try_to_vec().unwrap();
-        //msg!("token_map bytes len:{}",data_ser.len());
-        *token_map_len = (data_ser.len() as u32).to_le_bytes();
-        token_map_dst[..data_ser.len()].copy_from_slice(&data_ser);
+        pack_guardians(guardians, guardians_len_dst, guardians_dst)?;
+        *max_withdraw_validity_dst = max_withdraw_validity.to_le_bytes();
+        Ok(())
+    }
+}
+
+/// Token of a legacy perpetual account token map
+#[derive(BorshSerialize, BorshDeserialize, Debug, Copy, Clone)]
+pub struct LegacyMintProgram {
+    pub mint: Pubkey,
+    pub program_token_account: Pubkey,
+}
+
+/// Perpetual account before the discriminator, tokens in its token map
+#[derive(Debug, Clone)]
+pub struct LegacyPerpetual {
+    pub is_initialized: bool,
+    pub secp256k1_pubkey: [u8; 64],
+    pub gateway: Pubkey,
+    pub admin: Pubkey,
+    pub bump_seed: u8,
+    pub token_map: BTreeMap<TypeSymbol, LegacyMintProgram>,
+}
+
+impl LegacyPerpetual {
+    /// Unpacks a legacy perpetual account of LEGACY_PERPETUAL_BYTES
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < LEGACY_PERPETUAL_BYTES || !is_legacy(src) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, LEGACY_PERPETUAL_BYTES];
+        let (
+            is_initialized,
+            secp256k1_pubkey,
+            gateway,
+            admin,
+            bump_seed,
+            token_map_len,
+            token_map,
+        ) = array_refs![src, 1, 64, 32, 32, 1, 4, LEGACY_TOKENMAP_BYTES];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        //token map
+        let token_map_len = count_from_le(token_map_len);
+        let token_map = if token_map_len == 0 {
+            BTreeMap::new()
+        } else {
+            unpack_map(token_map, token_map_len)?
+        };
+        Ok(LegacyPerpetual {
+            is_initialized,
+            secp256k1_pubkey: *secp256k1_pubkey,
+            gateway: Pubkey::new_from_array(*gateway),
+            admin: Pubkey::new_from_array(*admin),
+            bump_seed: bump_seed[0],
+            token_map,
+        })
+    }
+
+    /// Packs into the first LEGACY_PERPETUAL_BYTES of the account, the tokens left
+    /// while MigrateAccount moves the token map in several transactions
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < LEGACY_PERPETUAL_BYTES {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let dst = array_mut_ref![dst, 0, LEGACY_PERPETUAL_BYTES];
+        let (
+            is_initialized_dst,
+            secp256k1_pubkey_dst,
+            gateway_dst,
+            admin_dst,
+            bump_seed_dst,
+            token_map_len,
+            token_map_dst,
+        ) = mut_array_refs![dst, 1, 64, 32, 32, 1, 4, LEGACY_TOKENMAP_BYTES];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        *secp256k1_pubkey_dst = self.secp256k1_pubkey;
+        gateway_dst.copy_from_slice(self.gateway.as_ref());
+        admin_dst.copy_from_slice(self.admin.as_ref());
+        bump_seed_dst[0] = self.bump_seed;
+        *token_map_len = pack_map(&self.token_map, token_map_dst)?;
+        Ok(())
+    }
+
+    /// Perpetual account of the current layout, the token map moves to token config accounts
+    pub fn migrate(&self) -> Perpetual {
+        let mut perpetual = Perpetual::new(self.secp256k1_pubkey, self.gateway, self.admin, self.bump_seed);
+        perpetual.is_initialized = self.is_initialized;
+        perpetual
+    }
+}
+
+/// User account before the discriminator, the last withdraw id per account type
+#[derive(Debug, Clone)]
+pub struct LegacyAccount {
+    pub is_initialized: bool,
+    pub user: Pubkey,
+    pub withdraw_id: BTreeMap<u8, u64>,
+}
+
+impl LegacyAccount {
+    /// Unpacks a legacy user account of LEGACY_ACCOUNT_BYTES
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < LEGACY_ACCOUNT_BYTES || !is_legacy(src) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, LEGACY_ACCOUNT_BYTES];
+        let (
+            is_initialized,
+            user,
+            withdrawid_len,
+            withdraw_id,
+        ) = array_refs![src, 1, 32, 1, LEGACY_WITHDRAWIDMAP_BYTES];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let withdrawid_len = withdrawid_len[0] as usize;
+        let withdraw_id = if withdrawid_len == 0 {
+            BTreeMap::new()
+        } else {
+            unpack_map(withdraw_id, withdrawid_len)?
+        };
+        Ok(LegacyAccount {
+            is_initialized,
+            user: Pubkey::new_from_array(*user),
+            withdraw_id,
+        })
+    }
+
+    /// User account of the current layout at the pda of `bump_seed`,
+    /// ids up to the last used one are dead
+    pub fn migrate(&self, bump_seed: u8) -> Result<Account, ProgramError> {
+        if self.withdraw_id.len() > MAX_ACCOUNT_TYPES {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut account = Account::new(self.user, bump_seed);
+        account.is_initialized = self.is_initialized;
+        for (account_type, last) in self.withdraw_id.iter() {
+            let mut window = WithdrawWindow::new();
+            window.base = last.saturating_add(1);
+            account.withdraw_id.insert(*account_type, window);
+        }
+        Ok(account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rng;
+
+    fn symbol(c: char) -> String {
+        c.to_string().repeat(MAX_SYMBOL_LEN)
+    }
+
+    //every account type and daily token in use, the largest ids and amounts
+    fn full_account() -> Account {
+        let mut account = Account::new(Pubkey::new_unique(), 254);
+        for account_type in 0..MAX_ACCOUNT_TYPES as u8 {
+            account.use_withdraw_id(account_type, MAX_WITHDRAW_ID).unwrap();
+            account.use_withdraw_id(account_type, MAX_WITHDRAW_ID - 200).unwrap();
+        }
+        for i in 0..MAX_DAILY_TOKENS as u8 {
+            let token = TypeSymbol { account_type: u8::MAX, symbol: symbol((b'A' + i) as char) };
+            account.use_daily_cap(&token, u64::MAX, u64::MAX, i64::MAX).unwrap();
+        }
+        account
+    }
+
+    #[test]
+    fn account_round_trip_at_limits() {
+        let account = full_account();
+        let mut data = vec![0; ACCOUNT_BYTES];
+        account.store(&mut data).unwrap();
+        let loaded = Account::load(&data).unwrap();
+        assert_eq!(loaded.user, account.user);
+        assert_eq!(loaded.bump_seed, 254);
+        assert_eq!(loaded.withdraw_id, account.withdraw_id);
+        assert_eq!(loaded.daily_withdrawn, account.daily_withdrawn);
+        assert_eq!(loaded.withdraw_id.len(), MAX_ACCOUNT_TYPES);
+        assert_eq!(loaded.daily_withdrawn.len(), MAX_DAILY_TOKENS);
+
+        //a new account round trips too
+        let account = Account::new(Pubkey::new_unique(), 255);
+        account.store(&mut data).unwrap();
+        let loaded = Account::load(&data).unwrap();
+        assert!(loaded.withdraw_id.is_empty() && loaded.daily_withdrawn.is_empty());
+    }
+
+    #[test]
+    fn account_over_limits() {
+        let mut account = full_account();
+        assert_eq!(account.use_withdraw_id(MAX_ACCOUNT_TYPES as u8, 1), Err(PerpError::WithdrawIdMapFull));
+        assert_eq!(account.cancel_withdraw_id(MAX_ACCOUNT_TYPES as u8, 1), Err(PerpError::WithdrawIdFail));
+        //an account type in use still works
+        account.use_withdraw_id(0, MAX_WITHDRAW_ID - 1).unwrap();
+
+        let mut data = vec![0; ACCOUNT_BYTES];
+        assert_eq!(account.store(&mut data[..ACCOUNT_BYTES - 1]), Err(ProgramError::AccountDataTooSmall));
+
+        //a map over its region fails to store instead of panicking
+        account.withdraw_id.insert(MAX_ACCOUNT_TYPES as u8, WithdrawWindow::new());
+        assert_eq!(account.store(&mut data), Err(PerpError::WithdrawIdMapFull.into()));
+        account.withdraw_id.remove(&(MAX_ACCOUNT_TYPES as u8));
+        let token = TypeSymbol { account_type: 0, symbol: "S".repeat(MAX_SYMBOL_LEN + 1) };
+        account.daily_withdrawn.insert(token, DailyWithdrawn::default());
+        assert_eq!(account.store(&mut data), Err(ProgramError::AccountDataTooSmall));
+    }
+
+    #[test]
+    fn daily_tokens_full() {
+        let now = 1_700_000_000;
+        let mut account = Account::new(Pubkey::new_unique(), 255);
+        let token = |i: u8| TypeSymbol { account_type: 1, symbol: symbol((b'A' + i) as char) };
+        for i in 0..MAX_DAILY_TOKENS as u8 {
+            account.use_daily_cap(&token(i), 1, 10, now).unwrap();
+        }
+        //one more token today, not a cap
+        let next = token(MAX_DAILY_TOKENS as u8);
+        assert_eq!(account.use_daily_cap(&next, 1, 10, now), Err(PerpError::DailyTokensFull));
+        assert_eq!(account.daily_withdrawn.len(), MAX_DAILY_TOKENS);
+        //tokens of today still count up to their cap
+        account.use_daily_cap(&token(0), 9, 10, now).unwrap();
+        assert_eq!(account.use_daily_cap(&token(0), 1, 10, now), Err(PerpError::DailyCapExceeded));
+        //the next day reuses the entries of past days
+        account.use_daily_cap(&next, 1, 10, now + DAY_SECONDS).unwrap();
+        assert_eq!(account.daily_withdrawn.len(), 1);
+    }
+
+    #[test]
+    fn account_load_never_panics() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut data = vec![0; ACCOUNT_BYTES];
+        full_account().store(&mut data).unwrap();
+        for _ in 0..2000 {
+            //random bytes and random map lengths behind a valid discriminator
+            let mut random = data.clone();
+            let at = DISCRIMINATOR_LEN + (rng.next() as usize % (ACCOUNT_BYTES - DISCRIMINATOR_LEN));
+            let len = rng.next() as usize % 16;
+            for byte in random.iter_mut().skip(at).take(len) {
+                *byte = rng.next() as u8;
+            }
+            let _ = Account::load(&random);
+            let len = rng.next() as usize % (ACCOUNT_BYTES + 8);
+            let _ = Account::load(&rng.bytes(len));
+            let _ = Account::load(&data[..len.min(ACCOUNT_BYTES)]);
+        }
+        assert_eq!(Account::load(&[]).unwrap_err(), PerpError::InvalidDiscriminator.into());
+    }
+
+    fn full_perpetual() -> Perpetual {
+        let mut perpetual = Perpetual::new([7; 64], Pubkey::new_unique(), Pubkey::new_unique(), 254);
+        perpetual.signers = (0..MAX_SIGNERS as u8).map(|i| [i + 1; 64]).collect();
+        perpetual.signer_threshold = MAX_SIGNERS as u8;
+        perpetual.pending_signers = (0..MAX_SIGNERS as u8).map(|i| [i + 100; 64]).collect();
+        perpetual.pending_signer_threshold = 1;
+        perpetual.guardians = (0..MAX_GUARDIANS).map(|_| Pubkey::new_unique()).collect();
+        perpetual.pause_flags = PAUSE_ALL;
+        perpetual.max_withdraw_validity = u64::MAX;
+        perpetual
+    }
+
+    #[test]
+    fn perpetual_round_trip_at_limits() {
+        let perpetual = full_perpetual();
+        let mut data = vec![0; PERPETUAL_BYTES];
+        perpetual.store(&mut data).unwrap();
+        let loaded = Perpetual::load(&data).unwrap();
+        assert_eq!(loaded.signers, perpetual.signers);
+        assert_eq!(loaded.pending_signers, perpetual.pending_signers);
+        assert_eq!(loaded.signer_threshold, perpetual.signer_threshold);
+        assert_eq!(loaded.guardians, perpetual.guardians);
+        assert_eq!(loaded.admin, perpetual.admin);
+        assert_eq!(loaded.gateway, perpetual.gateway);
+        assert_eq!(loaded.pause_flags, PAUSE_ALL);
+        assert_eq!(loaded.max_withdraw_validity, u64::MAX);
+        assert_eq!(loaded.bump_seed, 254);
+    }
+
+    #[test]
+    fn perpetual_over_limits() {
+        let mut data = vec![0; PERPETUAL_BYTES];
+        let perpetual = full_perpetual();
+        assert_eq!(perpetual.store(&mut data[..PERPETUAL_BYTES - 1]), Err(ProgramError::AccountDataTooSmall));
+        perpetual.store(&mut data).unwrap();
+        assert_eq!(Perpetual::load(&data[..PERPETUAL_BYTES - 1]).unwrap_err(), ProgramError::InvalidAccountData);
+
+        let mut signers = perpetual.clone();
+        signers.signers.push([9; 64]);
+        assert_eq!(signers.store(&mut data), Err(PerpError::InvalidSignerSet.into()));
+        let mut guardians = perpetual;
+        guardians.guardians.push(Pubkey::new_unique());
+        assert_eq!(guardians.store(&mut data), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn perpetual_load_uninitialized() {
+        let mut data = vec![0; PERPETUAL_BYTES];
+        let mut perpetual = full_perpetual();
+        perpetual.is_initialized = false;
+        perpetual.store(&mut data).unwrap();
+        assert_eq!(Perpetual::load(&data).unwrap_err(), ProgramError::UninitializedAccount);
+        assert!(!Perpetual::load_unchecked(&data).unwrap().is_initialized);
+    }
+
+    #[test]
+    fn discriminators_are_distinct() {
+        let mut account = vec![0; ACCOUNT_BYTES.max(PERPETUAL_BYTES)];
+        Account::new(Pubkey::new_unique(), 255).store(&mut account).unwrap();
+        assert_eq!(Perpetual::load(&account).unwrap_err(), PerpError::InvalidDiscriminator.into());
+        assert_eq!(TokenConfig::load(&account).unwrap_err(), PerpError::InvalidDiscriminator.into());
+        //legacy layout, is_initialized first
+        let mut legacy = vec![0; LEGACY_PERPETUAL_BYTES];
+        legacy[0] = 1;
+        assert_eq!(Perpetual::load(&legacy).unwrap_err(), PerpError::MigrationRequired.into());
+    }
+
+    #[test]
+    fn token_config_round_trip() {
+        let mut config = TokenConfig::new(3, symbol('Z'), 250).unwrap();
+        config.mint = Pubkey::new_unique();
+        config.rate_limit.window = 60;
+        config.cosign_amount = u64::MAX;
+        let mut data = vec![0; TOKEN_CONFIG_BYTES];
+        config.store(&mut data).unwrap();
+        assert_eq!(TokenConfig::load(&data).unwrap(), config);
+        assert_eq!(config.store(&mut data[..TOKEN_CONFIG_BYTES - 1]), Err(ProgramError::AccountDataTooSmall));
+        assert_eq!(TokenConfig::load(&data[..TOKEN_CONFIG_BYTES - 1]).unwrap_err(), ProgramError::InvalidAccountData);
+
+        //a symbol over its region fails to store instead of being truncated
+        for (symbol, e) in [("S".repeat(MAX_SYMBOL_LEN + 1), PerpError::SymbolTooLong), (String::new(), PerpError::EmptySymbol)] {
+            let mut long = config.clone();
+            long.symbol = symbol;
+            assert_eq!(long.store(&mut data), Err(e.into()));
+        }
+        assert_eq!(TokenConfig::load(&data).unwrap(), config);
+
+        let mut uninitialized = config.clone();
+        uninitialized.is_initialized = false;
+        uninitialized.store(&mut data).unwrap();
+        assert_eq!(TokenConfig::load(&data).unwrap_err(), ProgramError::UninitializedAccount);
+
+        assert!(matches!(TokenConfig::new(3, "S".repeat(MAX_SYMBOL_LEN + 1), 250), Err(PerpError::SymbolTooLong)));
+        assert!(matches!(TokenConfig::new(3, String::new(), 250), Err(PerpError::EmptySymbol)));
+    }
+
+    fn rate_limit(window: u64, max_amount: u64) -> RateLimit {
+        RateLimit { window, max_amount, ..RateLimit::default() }
+    }
+
+    #[test]
+    fn rate_limit_up_to_max() {
+        let now = 1_000_000;
+        let mut limit = rate_limit(100, 100);
+        limit.consume(60, now).unwrap();
+        limit.consume(40, now + 99).unwrap();
+        //crossing fails and counts nothing
+        assert_eq!(limit.consume(1, now + 99), Err(PerpError::RateLimited));
+        assert_eq!(limit.current, 100);
+        assert_eq!(rate_limit(100, 100).consume(101, now), Err(PerpError::RateLimited));
+        assert_eq!(rate_limit(100, u64::MAX).consume(u64::MAX, now), Ok(()));
+        //window 0 is no limit
+        assert_eq!(rate_limit(0, 0).consume(u64::MAX, now), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_decays() {
+        let now = 1_000_000;
+        let mut limit = rate_limit(100, 100);
+        limit.consume(100, now).unwrap();
+        //the previous window fully counts at the start of the next one
+        assert_eq!(limit.consume(1, now + 100), Err(PerpError::RateLimited));
+        //half a window later half of it is free
+        assert_eq!(limit.consume(51, now + 150), Err(PerpError::RateLimited));
+        limit.consume(50, now + 150).unwrap();
+        assert_eq!(limit.consume(1, now + 150), Err(PerpError::RateLimited));
+
+        //a whole window after the spent one ends, nothing of it counts
+        let mut limit = rate_limit(100, 100);
+        limit.consume(100, now).unwrap();
+        assert_eq!(limit.consume(100, now + 199), Err(PerpError::RateLimited));
+        limit.consume(99, now + 199).unwrap();
+        let mut limit = rate_limit(100, 100);
+        limit.consume(100, now).unwrap();
+        limit.consume(100, now + 200).unwrap();
+        assert_eq!(limit.previous, 0);
+    }
+
+    #[test]
+    fn rate_limit_clock_backwards() {
+        let now = 1_000_000;
+        let mut limit = rate_limit(100, 100);
+        limit.consume(100, now).unwrap();
+        limit.consume(50, now + 150).unwrap();
+        let rolled = limit;
+        //an earlier now counts the whole previous window, never frees anything
+        for earlier in [now + 149, now + 100, now, 0, i64::MIN] {
+            assert_eq!(limit.consume(1, earlier), Err(PerpError::RateLimited));
+            assert_eq!(limit, rolled);
+        }
+        limit.consume(1, now + 151).unwrap();
+    }
+
+    #[test]
+    fn withdraw_window_never_replays() {
+        let mut window = WithdrawWindow::new();
+        window.use_id(MAX_WITHDRAW_ID).unwrap();
+        assert_eq!(window.use_id(MAX_WITHDRAW_ID), Err(PerpError::WithdrawIdFail));
+        for withdrawid in [MAX_WITHDRAW_ID + 1, u64::MAX - 1, u64::MAX] {
+            assert_eq!(window.use_id(withdrawid), Err(PerpError::WithdrawIdFail));
+        }
+        //fill the whole window, base stops at MAX_WITHDRAW_ID + 1
+        for withdrawid in MAX_WITHDRAW_ID - WITHDRAW_WINDOW..MAX_WITHDRAW_ID {
+            let _ = window.use_id(withdrawid);
+        }
+        assert_eq!(window.base, MAX_WITHDRAW_ID + 1);
+        assert_eq!(window.use_id(MAX_WITHDRAW_ID), Err(PerpError::WithdrawIdFail));
+    }
+
+    #[test]
+    fn withdraw_window_out_of_order() {
+        let mut window = WithdrawWindow::new();
+        //any order inside the window, base only moves over the used prefix
+        for withdrawid in [5, 3, WITHDRAW_WINDOW, 1, 2] {
+            window.use_id(withdrawid).unwrap();
+        }
+        assert_eq!(window.base, 4);
+        assert!(!window.is_used(4));
+        window.use_id(4).unwrap();
+        assert_eq!(window.base, 6);
+        //each id once, below base or still in the bitmap
+        for withdrawid in [1, 2, 3, 4, 5, WITHDRAW_WINDOW] {
+            assert!(window.is_used(withdrawid));
+            assert_eq!(window.use_id(withdrawid), Err(PerpError::WithdrawIdFail));
+        }
+        assert_eq!(window.base, 6);
+    }
+
+    #[test]
+    fn withdraw_window_slide_kills_below() {
+        let mut window = WithdrawWindow::new();
+        window.use_id(10).unwrap();
+        //15 past the top slides base from 1 to 16
+        window.use_id(WITHDRAW_WINDOW + 15).unwrap();
+        assert_eq!(window.base, 16);
+        //unused ids below the new base are dead, the used one stays used
+        for withdrawid in [1, 2, 10, 15] {
+            assert_eq!(window.use_id(withdrawid), Err(PerpError::WithdrawIdFail));
+            assert_eq!(window.cancel_id(withdrawid), Err(PerpError::WithdrawIdFail));
+        }
+        assert_eq!(window.use_id(WITHDRAW_WINDOW + 15), Err(PerpError::WithdrawIdFail));
+        //the bit of the sliding id moved with the window
+        assert!(window.is_used(WITHDRAW_WINDOW + 15));
+        assert!(!window.is_used(WITHDRAW_WINDOW + 14));
+        window.use_id(16).unwrap();
+        assert_eq!(window.base, 17);
+    }
+
+    #[test]
+    fn withdraw_window_max_id() {
+        let mut window = WithdrawWindow::new();
+        window.use_id(MAX_WITHDRAW_ID).unwrap();
+        //the max id is the top of the window
+        assert_eq!(window.base, MAX_WITHDRAW_ID - (WITHDRAW_WINDOW - 1));
+        assert_eq!(window.use_id(window.base - 1), Err(PerpError::WithdrawIdFail));
+        window.use_id(window.base).unwrap();
+        window.use_id(MAX_WITHDRAW_ID - 1).unwrap();
+        assert_eq!(window.use_id(MAX_WITHDRAW_ID), Err(PerpError::WithdrawIdFail));
+        //nothing above, the window never reaches u64::MAX
+        assert_eq!(window.use_id(MAX_WITHDRAW_ID + 1), Err(PerpError::WithdrawIdFail));
+        assert_eq!(window.cancel_id(MAX_WITHDRAW_ID + 1), Err(PerpError::WithdrawIdFail));
+    }
+
+    #[test]
+    fn cancel_never_slides() {
+        let mut window = WithdrawWindow::new();
+        assert_eq!(window.cancel_id(WITHDRAW_WINDOW + 1), Err(PerpError::WithdrawIdFail));
+        assert_eq!(window.cancel_id(u64::MAX), Err(PerpError::WithdrawIdFail));
+        assert_eq!(window.base, 1);
+        window.cancel_id(WITHDRAW_WINDOW).unwrap();
+        assert_eq!(window.cancel_id(WITHDRAW_WINDOW), Err(PerpError::WithdrawIdFail));
+        window.use_id(1).unwrap();
+        assert_eq!(window.cancel_id(1), Err(PerpError::WithdrawIdFail));
+        assert_eq!(window.base, 2);
+        assert!(!window.is_used(2));
+    }
+
+    #[test]
+    fn cancel_burns_at_most_the_window() {
+        let mut window = WithdrawWindow::new();
+        //a run of cancels from base never moves it
+        for withdrawid in 1..=WITHDRAW_WINDOW {
+            window.cancel_id(withdrawid).unwrap();
+        }
+        assert_eq!(window.base, 1);
+        for withdrawid in [1, WITHDRAW_WINDOW, WITHDRAW_WINDOW + 1, 3 * WITHDRAW_WINDOW] {
+            assert_eq!(window.cancel_id(withdrawid), Err(PerpError::WithdrawIdFail));
+        }
+        //the next withdraw slides over the cancelled ids
+        window.use_id(WITHDRAW_WINDOW + 1).unwrap();
+        assert_eq!(window.base, WITHDRAW_WINDOW + 2);
+        window.cancel_id(WITHDRAW_WINDOW + 2).unwrap();
+        assert_eq!(window.base, WITHDRAW_WINDOW + 2);
+    }
+
+    #[test]
+    fn legacy_perpetual_round_trip_and_migrate() {
+        let mut token_map = BTreeMap::new();
+        for (account_type, symbol) in [(1, "BTC".to_string()), (1, "S".repeat(40)), (2, String::new())] {
+            let mint_program = LegacyMintProgram { mint: Pubkey::new_unique(), program_token_account: Pubkey::new_unique() };
+            token_map.insert(TypeSymbol { account_type, symbol }, mint_program);
+        }
+        let legacy = LegacyPerpetual {
+            is_initialized: true,
+            secp256k1_pubkey: [7; 64],
+            gateway: Pubkey::new_unique(),
+            admin: Pubkey::new_unique(),
+            bump_seed: 253,
+            token_map,
+        };
+        let mut data = vec![0; LEGACY_PERPETUAL_BYTES];
+        legacy.pack(&mut data).unwrap();
+        assert!(is_legacy(&data));
+        assert_eq!(Perpetual::load(&data).err(), Some(PerpError::MigrationRequired.into()));
+        let loaded = LegacyPerpetual::unpack(&data).unwrap();
+        assert_eq!(loaded.token_map.keys().collect::<Vec<_>>(), legacy.token_map.keys().collect::<Vec<_>>());
+        assert_eq!((loaded.gateway, loaded.admin, loaded.bump_seed), (legacy.gateway, legacy.admin, 253));
+
+        //a shrunk map packs over the old one
+        let mut shrunk = loaded.clone();
+        shrunk.token_map.retain(|token, _| token.account_type == 2);
+        shrunk.pack(&mut data).unwrap();
+        assert_eq!(LegacyPerpetual::unpack(&data).unwrap().token_map.len(), 1);
+        assert_eq!(shrunk.pack(&mut data[..LEGACY_PERPETUAL_BYTES - 1]), Err(ProgramError::AccountDataTooSmall));
+
+        let perpetual = legacy.migrate();
+        assert!(perpetual.is_initialized);
+        assert_eq!(perpetual.secp256k1_pubkey, [7; 64]);
+        assert_eq!((perpetual.gateway, perpetual.admin, perpetual.bump_seed), (legacy.gateway, legacy.admin, 253));
+        let mut data = vec![0; PERPETUAL_BYTES];
+        perpetual.store(&mut data).unwrap();
+        assert!(!is_legacy(&data));
+    }
+
+    #[test]
+    fn legacy_account_unpack_and_migrate() {
+        let user = Pubkey::new_unique();
+        let withdraw_id: BTreeMap<u8, u64> = [(1, 41), (3, MAX_WITHDRAW_ID + 5)].into_iter().collect();
+        let map = withdraw_id.try_to_vec().unwrap();
+        let mut data = vec![0; LEGACY_ACCOUNT_BYTES];
+        data[0] = 1;
+        data[1..33].copy_from_slice(user.as_ref());
+        data[33] = map.len() as u8;
+        data[34..34 + map.len()].copy_from_slice(&map);
+        assert_eq!(Account::load(&data).err(), Some(PerpError::MigrationRequired.into()));
+
+        let legacy = LegacyAccount::unpack(&data).unwrap();
+        assert_eq!((legacy.is_initialized, legacy.user), (true, user));
+        assert_eq!(legacy.withdraw_id, withdraw_id);
+        let mut account = legacy.migrate(252).unwrap();
+        assert_eq!((account.user, account.bump_seed), (user, 252));
+        //ids up to the last used one are dead, the next one is free
+        assert_eq!(account.withdraw_id[&1].base, 42);
+        assert_eq!(account.use_withdraw_id(1, 41), Err(PerpError::WithdrawIdFail));
+        account.use_withdraw_id(1, 42).unwrap();
+        //an account type past MAX_WITHDRAW_ID is dead for good
+        assert_eq!(account.use_withdraw_id(3, MAX_WITHDRAW_ID), Err(PerpError::WithdrawIdFail));
+        assert!(account.daily_withdrawn.is_empty());
+
+        //a full legacy map, 4 + 10 * 9 bytes, fits the new layout
+        let full: BTreeMap<u8, u64> = (0..10).map(|account_type| (account_type, u64::from(account_type) + 1)).collect();
+        let map = full.try_to_vec().unwrap();
+        assert!(map.len() <= LEGACY_WITHDRAWIDMAP_BYTES && map.len() + 9 > LEGACY_WITHDRAWIDMAP_BYTES);
+        let mut full_data = data.clone();
+        full_data[33] = map.len() as u8;
+        full_data[34..34 + map.len()].copy_from_slice(&map);
+        let account = LegacyAccount::unpack(&full_data).unwrap().migrate(252).unwrap();
+        let mut stored = vec![0; ACCOUNT_BYTES];
+        account.store(&mut stored).unwrap();
+        let loaded = Account::load(&stored).unwrap();
+        assert_eq!(loaded.withdraw_id.len(), 10);
+        assert!(loaded.withdraw_id.iter().all(|(account_type, window)| window.base == u64::from(*account_type) + 2));
+        //not the legacy layout, or short
+        assert!(LegacyAccount::unpack(&data[..LEGACY_ACCOUNT_BYTES - 1]).is_err());
+        data[0] = 2;
+        assert!(LegacyAccount::unpack(&data).is_err());
     }
 }